@@ -0,0 +1,346 @@
+use std::ops::Range;
+
+use crate::engine::{Error, Gradients, NodeId, Nullary, Op, Operations, check_len, check_node};
+
+/// Splits a graph into segments for gradient checkpointing, see
+/// `Operations::checkpoint_plan`.
+///
+/// Only the values of leaves, checkpoints and nodes that are read by a later
+/// segment are kept after the forward pass. The backward pass recomputes the
+/// other values one segment at a time, so the values that have to be stored
+/// are the kept values plus a single segment rather than the whole graph.
+#[derive(Debug, Clone)]
+pub struct CheckpointPlan {
+    node_count: usize,
+    segments: Vec<Range<usize>>,
+    slots: Vec<Option<usize>>,
+    kept_count: usize,
+    max_segment_len: usize,
+}
+
+impl CheckpointPlan {
+    /// The number of values that are kept between the forward and backward
+    /// pass.
+    #[inline]
+    pub fn kept_count(&self) -> usize {
+        self.kept_count
+    }
+
+    #[inline]
+    pub fn segment_count(&self) -> usize {
+        self.segments.len()
+    }
+
+    /// The number of values stored by `CheckpointedValues`: the kept values
+    /// plus scratch space for the longest segment.
+    #[inline]
+    pub fn stored_count(&self) -> usize {
+        self.kept_count + self.max_segment_len
+    }
+
+    /// Returns whether the value of `node` is kept after the forward pass.
+    #[inline]
+    pub fn is_kept(&self, node: NodeId) -> bool {
+        self.slot(node).is_some()
+    }
+
+    #[inline]
+    fn slot(&self, node: NodeId) -> Option<usize> {
+        self.slots.get(usize::from(node)).copied().flatten()
+    }
+
+    /// Allocates the buffers for this plan with every element initialized to
+    /// NaN.
+    #[inline]
+    pub fn values(&self) -> CheckpointedValues<'_> {
+        CheckpointedValues {
+            plan: self,
+            kept: vec![f64::NAN; self.kept_count],
+            scratch: vec![f64::NAN; self.max_segment_len],
+        }
+    }
+}
+
+/// The values of the nodes kept by a `CheckpointPlan`, along with scratch space
+/// to recompute a single segment.
+///
+/// Indexing gives access to the kept nodes, which always include the leaves
+/// whose values have to be set before the forward pass.
+#[derive(Debug)]
+pub struct CheckpointedValues<'p> {
+    plan: &'p CheckpointPlan,
+    kept: Vec<f64>,
+    scratch: Vec<f64>,
+}
+
+impl CheckpointedValues<'_> {
+    #[inline]
+    pub fn plan(&self) -> &CheckpointPlan {
+        self.plan
+    }
+}
+
+impl std::ops::Index<NodeId> for CheckpointedValues<'_> {
+    type Output = f64;
+
+    #[inline]
+    #[track_caller]
+    fn index(&self, node: NodeId) -> &Self::Output {
+        match self.plan.slot(node) {
+            Some(slot) => &self.kept[slot],
+            None => panic!("the value of node {} is not kept", usize::from(node)),
+        }
+    }
+}
+
+impl std::ops::IndexMut<NodeId> for CheckpointedValues<'_> {
+    #[inline]
+    #[track_caller]
+    fn index_mut(&mut self, node: NodeId) -> &mut Self::Output {
+        match self.plan.slot(node) {
+            Some(slot) => &mut self.kept[slot],
+            None => panic!("the value of node {} is not kept", usize::from(node)),
+        }
+    }
+}
+
+impl Operations {
+    /// Creates a checkpoint plan where every node in `checkpoints` ends a
+    /// segment.
+    ///
+    /// Panics if a checkpoint is not a node in this graph.
+    #[track_caller]
+    pub fn checkpoint_plan(&self, checkpoints: impl IntoIterator<Item = NodeId>) -> CheckpointPlan {
+        let mut ends = checkpoints
+            .into_iter()
+            .map(|node| match check_node(node, self.len()) {
+                Ok(()) => usize::from(node) + 1,
+                Err(error) => panic!("{}", error.display(self)),
+            })
+            .collect::<Vec<_>>();
+        ends.push(self.len());
+        ends.sort_unstable();
+        ends.dedup();
+        self.checkpoint_plan_from_ends(ends)
+    }
+
+    /// Creates a checkpoint plan that splits the graph into `segment_count`
+    /// segments with roughly the same number of nodes. About the square root of
+    /// the number of nodes minimizes the stored values for a chain.
+    pub fn checkpoint_plan_with_segments(&self, segment_count: usize) -> CheckpointPlan {
+        let segment_count = segment_count.clamp(1, self.len().max(1));
+        let ends = (1..=segment_count)
+            .map(|segment| self.len() * segment / segment_count)
+            .collect();
+        self.checkpoint_plan_from_ends(ends)
+    }
+
+    /// `ends` must be sorted, unique and end with the number of nodes.
+    fn checkpoint_plan_from_ends(&self, ends: Vec<usize>) -> CheckpointPlan {
+        let mut segments = Vec::with_capacity(ends.len());
+        let mut segment_of = vec![0; self.len()];
+        let mut start = 0;
+        for end in ends {
+            if end > start {
+                segment_of[start..end].fill(segments.len());
+                segments.push(start..end);
+                start = end;
+            }
+        }
+
+        // Keep the last node of every segment, the leaves that are set by the
+        // user and every value that is read by a later segment.
+        let mut keep = vec![false; self.len()];
+        for segment in &segments {
+            keep[segment.end - 1] = true;
+        }
+        for node in self.nodes() {
+            let index = usize::from(node);
+            if matches!(
+                self[node],
+                Op::Nullary(Nullary::Var | Nullary::Input | Nullary::Parameter)
+            ) {
+                keep[index] = true;
+            }
+            for operand in self[node].operands() {
+                if segment_of[usize::from(operand)] != segment_of[index] {
+                    keep[usize::from(operand)] = true;
+                }
+            }
+        }
+
+        let mut kept_count = 0;
+        let slots = keep
+            .into_iter()
+            .map(|keep| {
+                keep.then(|| {
+                    kept_count += 1;
+                    kept_count - 1
+                })
+            })
+            .collect();
+
+        CheckpointPlan {
+            node_count: self.len(),
+            max_segment_len: segments.iter().map(Range::len).max().unwrap_or_default(),
+            segments,
+            slots,
+            kept_count,
+        }
+    }
+
+    /// Computes the values of all nodes, keeping only the values selected by
+    /// the plan.
+    ///
+    /// Panics if the plan was made for a graph with a different number of
+    /// nodes.
+    #[track_caller]
+    pub fn forward_checkpointed(&self, values: &mut CheckpointedValues) {
+        if let Err(error) = self.try_forward_checkpointed(values) {
+            panic!("{}", error.display(self));
+        }
+    }
+
+    pub fn try_forward_checkpointed(&self, values: &mut CheckpointedValues) -> Result<(), Error> {
+        check_len(self.len(), values.plan.node_count)?;
+        for segment in 0..values.plan.segments.len() {
+            self.forward_segment(values, segment);
+        }
+        Ok(())
+    }
+
+    /// Computes the same gradients as `backward` from the values stored by
+    /// `forward_checkpointed`, recomputing one segment at a time.
+    ///
+    /// Panics if the plan was made for a graph with a different number of
+    /// nodes, if `gradients` does not have one element per node or if `target`
+    /// is not a node in this graph.
+    #[track_caller]
+    pub fn backward_checkpointed(
+        &self,
+        values: &mut CheckpointedValues,
+        gradients: &mut Gradients,
+        target: NodeId,
+        gradient: f64,
+    ) {
+        if let Err(error) = self.try_backward_checkpointed(values, gradients, target, gradient) {
+            panic!("{}", error.display(self));
+        }
+    }
+
+    pub fn try_backward_checkpointed(
+        &self,
+        values: &mut CheckpointedValues,
+        gradients: &mut Gradients,
+        target: NodeId,
+        gradient: f64,
+    ) -> Result<(), Error> {
+        check_len(self.len(), values.plan.node_count)?;
+        check_len(self.len(), gradients.len())?;
+        check_node(target, self.len())?;
+
+        gradients.fill(0.0);
+        let end = self.seed(gradients, [(target, gradient)])?;
+
+        let plan = values.plan;
+        for (segment, range) in plan.segments.iter().enumerate().rev() {
+            if range.start >= end {
+                continue;
+            }
+            self.forward_segment(values, segment);
+            let CheckpointedValues { kept, scratch, .. } = &*values;
+            let value = |node: NodeId| {
+                let index = usize::from(node);
+                if range.contains(&index) {
+                    scratch[index - range.start]
+                } else {
+                    kept[plan.slots[index].expect("values read across segments are kept")]
+                }
+            };
+            self.propagate_range(value, gradients, range.start..range.end.min(end));
+        }
+
+        Ok(())
+    }
+
+    /// Computes the values of the nodes in a segment into the scratch space,
+    /// copying the ones that are kept.
+    fn forward_segment(&self, values: &mut CheckpointedValues, segment: usize) {
+        let CheckpointedValues { plan, kept, scratch } = values;
+        let range = plan.segments[segment].clone();
+        for index in range.clone() {
+            let value = |node: NodeId| {
+                let index = usize::from(node);
+                if range.contains(&index) {
+                    scratch[index - range.start]
+                } else {
+                    kept[plan.slots[index].expect("values read across segments are kept")]
+                }
+            };
+            let node = NodeId::from(index);
+            let output = match self[node] {
+                Op::Nullary(Nullary::Var | Nullary::Input | Nullary::Parameter) => {
+                    kept[plan.slots[index].expect("leaves are kept")]
+                }
+                Op::Nullary(Nullary::Constant(constant)) => constant.value(),
+                Op::Unary(unary, a) => unary.forward(value(a)),
+                Op::Binary(binary, (a, b)) => binary.forward(value(a), value(b)),
+            };
+            scratch[index - range.start] = output;
+            if let Some(slot) = plan.slots[index] {
+                kept[slot] = output;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::Values;
+
+    fn deep_chain() -> (Operations, [NodeId; 2], NodeId) {
+        let mut ops = Operations::default();
+        let [a, x] = ops.vars();
+        let mut y = x;
+        for _ in 0..200 {
+            y = ops.insert((a * y).tanh() + x);
+        }
+        (ops, [a, x], y)
+    }
+
+    #[test]
+    fn gradients_match_backward() {
+        let (ops, [a, x], y) = deep_chain();
+
+        let mut values = Values::new(ops.len());
+        values[a] = 0.9;
+        values[x] = 0.4;
+        ops.forward(&mut values);
+        let mut expected = Gradients::new(ops.len());
+        ops.backward(&values, &mut expected, y, 1.0);
+
+        let segmented = ops.checkpoint_plan_with_segments(20);
+        assert_eq!(segmented.segment_count(), 20);
+        assert!(segmented.stored_count() < ops.len() / 4);
+
+        let explicit = ops.checkpoint_plan([NodeId::from(300), NodeId::from(50)]);
+        assert_eq!(explicit.segment_count(), 3);
+        assert!(explicit.is_kept(NodeId::from(50)));
+
+        for plan in [segmented, explicit] {
+            let mut checkpointed = plan.values();
+            checkpointed[a] = 0.9;
+            checkpointed[x] = 0.4;
+            ops.forward_checkpointed(&mut checkpointed);
+            assert_eq!(checkpointed[y], values[y]);
+
+            let mut gradients = Gradients::new(ops.len());
+            ops.backward_checkpointed(&mut checkpointed, &mut gradients, y, 1.0);
+            assert_eq!(
+                gradients.iter().collect::<Vec<_>>(),
+                expected.iter().collect::<Vec<_>>()
+            );
+        }
+    }
+}
@@ -1,8 +1,50 @@
 use core::f64;
+use std::panic::Location;
 
+use crate::metadata::{DisplayWithNames, Metadata, NodeDisplay, WithNames};
+
+/// An unclassified leaf node whose value is set by the user.
 #[derive(Copy, Clone)]
 pub struct Var;
 
+/// A leaf node holding data that is fed into the graph, like a training sample.
+#[derive(Copy, Clone)]
+pub struct Input;
+
+/// A leaf node holding trainable state, like a weight or a bias.
+#[derive(Copy, Clone)]
+pub struct Parameter;
+
+/// A leaf node with a fixed value which is written by the forward pass. The
+/// value is stored as its bit pattern so that `Op` can remain `Eq`.
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub struct Constant(u64);
+
+impl Constant {
+    #[inline]
+    pub fn new(value: f64) -> Self {
+        Self(value.to_bits())
+    }
+
+    #[inline]
+    pub fn value(self) -> f64 {
+        f64::from_bits(self.0)
+    }
+}
+
+impl From<f64> for Constant {
+    #[inline]
+    fn from(value: f64) -> Self {
+        Self::new(value)
+    }
+}
+
+impl std::fmt::Debug for Constant {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("Constant").field(&self.value()).finish()
+    }
+}
+
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub struct Id(pub(crate) usize);
 
@@ -11,6 +53,91 @@ pub struct Expr<T>(pub(crate) T);
 
 pub type NodeId = Expr<Id>;
 
+/// Errors reported by the fallible (`try_`) variants of the engine operations.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Error {
+    /// A buffer does not have the number of elements required by the graph.
+    LengthMismatch { expected: usize, actual: usize },
+    /// A node does not exist in a graph of `len` nodes.
+    NodeOutOfRange { node: NodeId, len: usize },
+    /// A function was called with the wrong number of arguments.
+    ArgumentCountMismatch { expected: usize, actual: usize },
+    /// A node would read an operand that is not computed before it.
+    ForwardReference { node: NodeId, operand: NodeId },
+    /// The operands of an elementwise reduction such as `dot` have different
+    /// lengths.
+    OperandLengthMismatch { left: usize, right: usize },
+}
+
+impl Error {
+    /// Displays the error with the nodes of `ops` shown by name, see
+    /// `Operations::display_node`.
+    #[inline]
+    pub fn display<'a>(&'a self, ops: &'a Operations) -> impl std::fmt::Display + 'a {
+        WithNames {
+            value: self,
+            ops: Some(ops),
+        }
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.fmt_with_names(f, None)
+    }
+}
+
+impl DisplayWithNames for Error {
+    fn fmt_with_names(&self, f: &mut std::fmt::Formatter<'_>, ops: Option<&Operations>) -> std::fmt::Result {
+        let node = |node| NodeDisplay { ops, node };
+        match *self {
+            Error::LengthMismatch { expected, actual } => {
+                write!(f, "buffer length mismatch: expected {expected} but got {actual}")
+            }
+            Error::NodeOutOfRange {
+                node: out_of_range,
+                len,
+            } => write!(
+                f,
+                "node {} is out of range for a graph with {len} nodes, are you using a node from another graph?",
+                node(out_of_range)
+            ),
+            Error::ArgumentCountMismatch { expected, actual } => {
+                write!(f, "argument count mismatch: expected {expected} but got {actual}")
+            }
+            Error::ForwardReference { node: reader, operand } => write!(
+                f,
+                "node {} cannot read node {} because it is not computed before it",
+                node(reader),
+                node(operand)
+            ),
+            Error::OperandLengthMismatch { left, right } => {
+                write!(f, "operand length mismatch: {left} and {right} nodes")
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[inline]
+pub(crate) fn check_len(expected: usize, actual: usize) -> Result<(), Error> {
+    if expected == actual {
+        Ok(())
+    } else {
+        Err(Error::LengthMismatch { expected, actual })
+    }
+}
+
+#[inline]
+pub(crate) fn check_node(node: NodeId, len: usize) -> Result<(), Error> {
+    if usize::from(node) < len {
+        Ok(())
+    } else {
+        Err(Error::NodeOutOfRange { node, len })
+    }
+}
+
 impl From<usize> for NodeId {
     fn from(value: usize) -> Self {
         Expr(Id(value))
@@ -105,6 +232,22 @@ macro_rules! impl_buffer {
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum Nullary {
     Var,
+    Input,
+    Parameter,
+    Constant(Constant),
+}
+
+impl Nullary {
+    /// Returns a short lowercase name for this kind of leaf.
+    #[inline]
+    pub fn name(self) -> &'static str {
+        match self {
+            Nullary::Var => "var",
+            Nullary::Input => "input",
+            Nullary::Parameter => "parameter",
+            Nullary::Constant(_) => "constant",
+        }
+    }
 }
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
@@ -119,9 +262,48 @@ pub enum Unary {
     ExpM1,
     TanH,
     ReLU,
+    /// Stop-gradient: the identity in the forward pass with a zero gradient.
+    Detach,
+    /// Straight-through estimator: rounds in the forward pass but passes the
+    /// gradient through unchanged as if it were the identity.
+    RoundSTE,
 }
 
 impl Unary {
+    pub const ALL: [Unary; 12] = [
+        Unary::Neg,
+        Unary::Recip,
+        Unary::Pow2,
+        Unary::Ln,
+        Unary::Ln1P,
+        Unary::Exp,
+        Unary::Exp2,
+        Unary::ExpM1,
+        Unary::TanH,
+        Unary::ReLU,
+        Unary::Detach,
+        Unary::RoundSTE,
+    ];
+
+    /// Returns the name of the `Expr` method that builds this operation.
+    #[inline]
+    pub fn name(self) -> &'static str {
+        match self {
+            Unary::Neg => "neg",
+            Unary::Recip => "recip",
+            Unary::Pow2 => "pow_2",
+            Unary::Ln => "ln",
+            Unary::Ln1P => "ln_1p",
+            Unary::Exp => "exp",
+            Unary::Exp2 => "exp_2",
+            Unary::ExpM1 => "exp_m1",
+            Unary::TanH => "tanh",
+            Unary::ReLU => "relu",
+            Unary::Detach => "detach",
+            Unary::RoundSTE => "round_ste",
+        }
+    }
+
     #[inline]
     pub fn forward(self, a: f64) -> f64 {
         match self {
@@ -135,6 +317,8 @@ impl Unary {
             Unary::ExpM1 => a.exp_m1(),
             Unary::TanH => a.tanh(),
             Unary::ReLU => a.max(0.0),
+            Unary::Detach => a,
+            Unary::RoundSTE => a.round(),
         }
     }
 
@@ -159,6 +343,8 @@ impl Unary {
                     0.0
                 }
             }
+            Unary::Detach => 0.0,
+            Unary::RoundSTE => 1.0,
         }
     }
 }
@@ -170,9 +356,43 @@ pub enum Binary {
     Mul,
     Div,
     Pow,
+    /// The identity on the first operand in the forward pass, with its gradient
+    /// scaled by the second operand in the backward pass. The scale is treated
+    /// as a constant and receives no gradient. A negative scale gives a
+    /// gradient reversal layer as used in adversarial training.
+    ScaleGrad,
+    /// The larger operand. The gradient flows only to the operand that is
+    /// selected, and on ties that is the first one, so `max(a, b)` and
+    /// `max(b, a)` have the same value but different gradients. The first
+    /// operand is also selected if the second one is NaN.
+    Max,
 }
 
 impl Binary {
+    pub const ALL: [Binary; 7] = [
+        Binary::Add,
+        Binary::Sub,
+        Binary::Mul,
+        Binary::Div,
+        Binary::Pow,
+        Binary::ScaleGrad,
+        Binary::Max,
+    ];
+
+    /// Returns the name of the `Expr` method that builds this operation.
+    #[inline]
+    pub fn name(self) -> &'static str {
+        match self {
+            Binary::Add => "add",
+            Binary::Sub => "sub",
+            Binary::Mul => "mul",
+            Binary::Div => "div",
+            Binary::Pow => "pow",
+            Binary::ScaleGrad => "scale_grad",
+            Binary::Max => "max",
+        }
+    }
+
     #[inline]
     pub fn forward(self, a: f64, b: f64) -> f64 {
         match self {
@@ -181,6 +401,14 @@ impl Binary {
             Binary::Mul => a * b,
             Binary::Div => a / b,
             Binary::Pow => a.powf(b),
+            Binary::ScaleGrad => a,
+            Binary::Max => {
+                if b > a {
+                    b
+                } else {
+                    a
+                }
+            }
         }
     }
 
@@ -201,6 +429,14 @@ impl Binary {
                 // 0.0 intead of the correct 0.0.
                 (b * a.powf(b - 1.0), a.ln() * c)
             }
+            Binary::ScaleGrad => (b, 0.0),
+            Binary::Max => {
+                if b > a {
+                    (0.0, 1.0)
+                } else {
+                    (1.0, 0.0)
+                }
+            }
         }
     }
 }
@@ -212,18 +448,51 @@ pub enum Op {
     Binary(Binary, (NodeId, NodeId)),
 }
 
-pub trait Insertable {
+impl Op {
+    #[inline]
+    pub fn name(self) -> &'static str {
+        match self {
+            Op::Nullary(nullary) => nullary.name(),
+            Op::Unary(unary, _) => unary.name(),
+            Op::Binary(binary, _) => binary.name(),
+        }
+    }
+
+    /// Returns the nodes this operation reads from.
+    #[inline]
+    pub fn operands(self) -> impl Iterator<Item = NodeId> {
+        let operands = match self {
+            Op::Nullary(_) => [None, None],
+            Op::Unary(_, a) => [Some(a), None],
+            Op::Binary(_, (a, b)) => [Some(a), Some(b)],
+        };
+        operands.into_iter().flatten()
+    }
+}
+
+pub trait Insertable: Sized {
     type Output;
 
-    fn insert_into(self, ops: &mut Operations) -> Self::Output;
+    /// Inserts self into the graph, failing if it refers to nodes that do not
+    /// exist in `ops`.
+    fn try_insert_into(self, ops: &mut Operations) -> Result<Self::Output, Error>;
+
+    #[inline]
+    #[track_caller]
+    fn insert_into(self, ops: &mut Operations) -> Self::Output {
+        match self.try_insert_into(ops) {
+            Ok(output) => output,
+            Err(error) => panic!("{}", error.display(ops)),
+        }
+    }
 }
 
 impl<A: Insertable, B: Insertable> Insertable for (A, B) {
     type Output = (A::Output, B::Output);
 
     #[inline]
-    fn insert_into(self, ops: &mut Operations) -> Self::Output {
-        (ops.insert(self.0), ops.insert(self.1))
+    fn try_insert_into(self, ops: &mut Operations) -> Result<Self::Output, Error> {
+        Ok((ops.try_insert(self.0)?, ops.try_insert(self.1)?))
     }
 }
 
@@ -231,8 +500,17 @@ impl<T: Insertable, const N: usize> Insertable for [T; N] {
     type Output = [T::Output; N];
 
     #[inline]
-    fn insert_into(self, ops: &mut Operations) -> Self::Output {
-        self.map(|item| ops.insert(item))
+    fn try_insert_into(self, ops: &mut Operations) -> Result<Self::Output, Error> {
+        // The items after the first error are not inserted.
+        let mut error = None;
+        let outputs = self.map(|item| match error {
+            Some(_) => None,
+            None => ops.try_insert(item).map_err(|e| error = Some(e)).ok(),
+        });
+        match error {
+            Some(error) => Err(error),
+            None => Ok(outputs.map(|output| output.expect("every item was inserted"))),
+        }
     }
 }
 
@@ -240,9 +518,9 @@ impl Insertable for Id {
     type Output = NodeId;
 
     #[inline]
-    fn insert_into(self, ops: &mut Operations) -> NodeId {
-        assert!(self.0 < ops.len(), "Are you using a node from another graph?");
-        Expr(self)
+    fn try_insert_into(self, ops: &mut Operations) -> Result<NodeId, Error> {
+        check_node(Expr(self), ops.len())?;
+        Ok(Expr(self))
     }
 }
 
@@ -250,8 +528,8 @@ impl<I: Insertable> Insertable for Expr<I> {
     type Output = I::Output;
 
     #[inline]
-    fn insert_into(self, ops: &mut Operations) -> Self::Output {
-        self.0.insert_into(ops)
+    fn try_insert_into(self, ops: &mut Operations) -> Result<Self::Output, Error> {
+        self.0.try_insert_into(ops)
     }
 }
 
@@ -259,8 +537,35 @@ impl Insertable for Var {
     type Output = NodeId;
 
     #[inline]
-    fn insert_into(self, ops: &mut Operations) -> Self::Output {
-        ops.insert(Op::Nullary(Nullary::Var))
+    fn try_insert_into(self, ops: &mut Operations) -> Result<Self::Output, Error> {
+        ops.try_insert(Op::Nullary(Nullary::Var))
+    }
+}
+
+impl Insertable for Input {
+    type Output = NodeId;
+
+    #[inline]
+    fn try_insert_into(self, ops: &mut Operations) -> Result<Self::Output, Error> {
+        ops.try_insert(Op::Nullary(Nullary::Input))
+    }
+}
+
+impl Insertable for Parameter {
+    type Output = NodeId;
+
+    #[inline]
+    fn try_insert_into(self, ops: &mut Operations) -> Result<Self::Output, Error> {
+        ops.try_insert(Op::Nullary(Nullary::Parameter))
+    }
+}
+
+impl Insertable for Constant {
+    type Output = NodeId;
+
+    #[inline]
+    fn try_insert_into(self, ops: &mut Operations) -> Result<Self::Output, Error> {
+        ops.try_insert(Op::Nullary(Nullary::Constant(self)))
     }
 }
 
@@ -268,10 +573,20 @@ impl Insertable for Op {
     type Output = NodeId;
 
     #[inline]
-    fn insert_into(self, ops: &mut Operations) -> NodeId {
-        let id = NodeId::from(ops.0.len());
+    fn try_insert_into(self, ops: &mut Operations) -> Result<NodeId, Error> {
+        let len = ops.len();
+        match self {
+            Op::Nullary(_) => {}
+            Op::Unary(_, input) => check_node(input, len)?,
+            Op::Binary(_, (a, b)) => {
+                check_node(a, len)?;
+                check_node(b, len)?;
+            }
+        }
+        let id = NodeId::from(len);
         ops.0.push(self);
-        id
+        ops.1.on_insert(id);
+        Ok(id)
     }
 }
 
@@ -333,11 +648,20 @@ impl Gradients {
     // NOTE: Decided against implementing AddAssign because it requires Add
     // which would alloc.
     #[inline]
+    #[track_caller]
     pub fn accumulate(&mut self, rhs: &Gradients) {
-        assert_eq!(self.len(), rhs.len());
+        if let Err(error) = self.try_accumulate(rhs) {
+            panic!("{error}");
+        }
+    }
+
+    #[inline]
+    pub fn try_accumulate(&mut self, rhs: &Gradients) -> Result<(), Error> {
+        check_len(self.len(), rhs.len())?;
         for i in 0..self.len() {
             self.0[i] += rhs.0[i]
         }
+        Ok(())
     }
 }
 
@@ -345,71 +669,351 @@ impl_index_node_id!(Gradients, f64);
 
 impl_buffer!(Gradients, f64);
 
+/// Marks the nodes through which a gradient has to flow to reach the nodes that
+/// require a gradient. Created by `Operations::gradient_mask`.
+#[derive(Debug, Clone)]
+pub struct GradientMask(Vec<bool>);
+
+impl std::ops::Index<NodeId> for GradientMask {
+    type Output = bool;
+
+    #[inline]
+    fn index(&self, index: NodeId) -> &Self::Output {
+        &self.0[usize::from(index)]
+    }
+}
+
+/// The computation graph: a list of operations in topological order, along
+/// with optional `Metadata` about its nodes.
 #[derive(Debug, Default)]
-pub struct Operations(Vec<Op>);
+pub struct Operations(Vec<Op>, pub(crate) Metadata);
 
 impl Operations {
     #[inline]
+    #[track_caller]
     pub fn insert<I: Insertable>(&mut self, insertable: I) -> I::Output {
-        insertable.insert_into(self)
+        match self.try_insert(insertable) {
+            Ok(output) => output,
+            Err(error) => panic!("{}", error.display(self)),
+        }
+    }
+
+    /// Inserts `insertable` into the graph. If it fails, any nodes that were
+    /// inserted before the failure are removed again.
+    #[inline]
+    #[track_caller]
+    pub fn try_insert<I: Insertable>(&mut self, insertable: I) -> Result<I::Output, Error> {
+        Metadata::with_caller(self, Location::caller(), |ops| {
+            let len = ops.len();
+            insertable.try_insert_into(ops).inspect_err(|_| ops.truncate(len))
+        })
     }
 
+    /// Lazily inserts every item of `collection`. The nodes are recorded as
+    /// inserted by the caller of `extend`.
     #[inline]
+    #[track_caller]
     pub fn extend<I>(&mut self, collection: I) -> impl Iterator<Item = <I::Item as Insertable>::Output>
     where
         I: IntoIterator,
         I::Item: Insertable,
     {
-        collection.into_iter().map(|item| self.insert(item))
+        // The iterator runs after this function returned, so the location has
+        // to be captured here.
+        let location = Location::caller();
+        collection
+            .into_iter()
+            .map(move |item| Metadata::with_caller(self, location, |ops| ops.insert(item)))
     }
 
     #[inline]
+    #[track_caller]
     pub fn var(&mut self) -> NodeId {
         self.insert(Var)
     }
 
     #[inline]
+    #[track_caller]
     pub fn vars<const N: usize>(&mut self) -> [NodeId; N] {
         self.insert([Var; N])
     }
 
     #[inline]
+    #[track_caller]
     pub fn vars_iter(&mut self, count: usize) -> impl Iterator<Item = NodeId> {
         self.extend(std::iter::repeat_n(Var, count))
     }
 
     #[inline]
+    #[track_caller]
     pub fn vars_vec(&mut self, count: usize) -> Vec<NodeId> {
-        self.vars_iter(count).collect()
+        Metadata::with_caller(self, Location::caller(), |ops| ops.vars_iter(count).collect())
+    }
+
+    #[inline]
+    #[track_caller]
+    pub fn input_vec(&mut self, count: usize) -> Vec<NodeId> {
+        Metadata::with_caller(self, Location::caller(), |ops| {
+            ops.extend(std::iter::repeat_n(Input, count)).collect()
+        })
+    }
+
+    #[inline]
+    #[track_caller]
+    pub fn parameter_vec(&mut self, count: usize) -> Vec<NodeId> {
+        Metadata::with_caller(self, Location::caller(), |ops| {
+            ops.extend(std::iter::repeat_n(Parameter, count)).collect()
+        })
+    }
+
+    #[inline]
+    #[track_caller]
+    pub fn constant(&mut self, value: f64) -> NodeId {
+        self.insert(Constant::new(value))
+    }
+
+    /// Returns the leaf nodes of the given kind.
+    #[inline]
+    fn leaves(&self, kind: Nullary) -> impl Iterator<Item = NodeId> {
+        self.nodes().filter(move |&node| self[node] == Op::Nullary(kind))
+    }
+
+    /// Returns all trainable leaf nodes in insertion order.
+    #[inline]
+    pub fn parameters(&self) -> impl Iterator<Item = NodeId> {
+        self.leaves(Nullary::Parameter)
+    }
+
+    /// Returns all input leaf nodes in insertion order.
+    #[inline]
+    pub fn inputs(&self) -> impl Iterator<Item = NodeId> {
+        self.leaves(Nullary::Input)
+    }
+
+    /// Removes the nodes from `len` onwards along with their metadata.
+    #[inline]
+    pub(crate) fn truncate(&mut self, len: usize) {
+        self.0.truncate(len);
+        self.1.truncate(len);
     }
 
     #[inline]
     pub fn clear(&mut self) {
         self.0.clear();
+        self.1.clear();
     }
 
+    /// Computes the values of all nodes except for the variable, input and
+    /// parameter leaves.
+    ///
+    /// Panics if `values` does not have one element per node.
+    #[track_caller]
     pub fn forward(&self, values: &mut Values) {
-        debug_assert_eq!(self.0.len(), values.0.len());
+        if let Err(error) = self.try_forward(values) {
+            panic!("{}", error.display(self));
+        }
+    }
+
+    pub fn try_forward(&self, values: &mut Values) -> Result<(), Error> {
+        check_len(self.len(), values.len())?;
 
         for output in self.nodes() {
             match self[output] {
-                Op::Nullary(Nullary::Var) => {
+                Op::Nullary(Nullary::Var | Nullary::Input | Nullary::Parameter) => {
                     // Nothing to do.
                 }
+                Op::Nullary(Nullary::Constant(constant)) => values[output] = constant.value(),
                 Op::Unary(unary, input) => values[output] = unary.forward(values[input]),
                 Op::Binary(binary, input) => values[output] = binary.forward(values[input.0], values[input.1]),
             }
         }
+
+        Ok(())
     }
 
+    /// Computes the gradients of `target` with respect to every node, with the
+    /// gradient of `target` itself set to `gradient`.
+    ///
+    /// Panics if `values` or `gradients` do not have one element per node or if
+    /// `target` is not a node in this graph.
+    #[track_caller]
     pub fn backward(&self, values: &Values, gradients: &mut Gradients, target: NodeId, gradient: f64) {
-        debug_assert_eq!(self.len(), values.len());
-        debug_assert_eq!(self.len(), gradients.len());
+        if let Err(error) = self.try_backward(values, gradients, target, gradient) {
+            panic!("{}", error.display(self));
+        }
+    }
+
+    pub fn try_backward(
+        &self,
+        values: &Values,
+        gradients: &mut Gradients,
+        target: NodeId,
+        gradient: f64,
+    ) -> Result<(), Error> {
+        self.try_backward_many(values, gradients, [(target, gradient)])
+    }
+
+    /// Computes the vector-Jacobian product for several seeded outputs in a
+    /// single reverse pass. Seeds for the same node are summed.
+    ///
+    /// Panics if `values` or `gradients` do not have one element per node or if
+    /// a seeded node is not a node in this graph.
+    #[track_caller]
+    pub fn backward_many(
+        &self,
+        values: &Values,
+        gradients: &mut Gradients,
+        seeds: impl IntoIterator<Item = (NodeId, f64)>,
+    ) {
+        if let Err(error) = self.try_backward_many(values, gradients, seeds) {
+            panic!("{}", error.display(self));
+        }
+    }
+
+    pub fn try_backward_many(
+        &self,
+        values: &Values,
+        gradients: &mut Gradients,
+        seeds: impl IntoIterator<Item = (NodeId, f64)>,
+    ) -> Result<(), Error> {
+        check_len(self.len(), values.len())?;
+        check_len(self.len(), gradients.len())?;
+
+        gradients.fill(0.0);
+        let end = self.seed(gradients, seeds)?;
+        self.propagate(values, gradients, end);
+
+        Ok(())
+    }
+
+    /// Like `backward` but adds the gradients of the leaf nodes to the existing
+    /// contents of `gradients` instead of overwriting them. This allows
+    /// accumulating gradients over the samples of a mini-batch in a single
+    /// buffer.
+    ///
+    /// The gradients of non-leaf nodes are reset before the reverse pass and
+    /// only hold the gradients of this pass afterwards. Accumulating them too
+    /// would propagate the gradients of previous passes again.
+    #[track_caller]
+    pub fn backward_accumulate(&self, values: &Values, gradients: &mut Gradients, target: NodeId, gradient: f64) {
+        if let Err(error) = self.try_backward_accumulate(values, gradients, target, gradient) {
+            panic!("{}", error.display(self));
+        }
+    }
+
+    pub fn try_backward_accumulate(
+        &self,
+        values: &Values,
+        gradients: &mut Gradients,
+        target: NodeId,
+        gradient: f64,
+    ) -> Result<(), Error> {
+        check_len(self.len(), values.len())?;
+        check_len(self.len(), gradients.len())?;
+        check_node(target, self.len())?;
+
+        for node in self.nodes() {
+            if !matches!(self[node], Op::Nullary(_)) {
+                gradients[node] = 0.0;
+            }
+        }
+        let end = self.seed(gradients, [(target, gradient)])?;
+        self.propagate(values, gradients, end);
+
+        Ok(())
+    }
+
+    /// Adds the seeds to `gradients` and returns the end of the range of nodes
+    /// that the reverse pass has to visit. Nodes after the highest seeded node
+    /// can not have a gradient.
+    pub(crate) fn seed(
+        &self,
+        gradients: &mut Gradients,
+        seeds: impl IntoIterator<Item = (NodeId, f64)>,
+    ) -> Result<usize, Error> {
+        let mut end = 0;
+        for (node, gradient) in seeds {
+            check_node(node, self.len())?;
+            gradients[node] += gradient;
+            end = end.max(usize::from(node) + 1);
+        }
+        Ok(end)
+    }
+
+    /// Returns a mask of the nodes that can reach any of the nodes in
+    /// `requires_grad`, for use with `backward_masked`.
+    #[track_caller]
+    pub fn gradient_mask(&self, requires_grad: impl IntoIterator<Item = NodeId>) -> GradientMask {
+        let mut mask = vec![false; self.len()];
+        for node in requires_grad {
+            if let Err(error) = check_node(node, self.len()) {
+                panic!("{}", error.display(self));
+            }
+            mask[usize::from(node)] = true;
+        }
+        for node in self.nodes() {
+            let index = usize::from(node);
+            mask[index] |= match self[node] {
+                Op::Nullary(_) => false,
+                Op::Unary(_, a) => mask[usize::from(a)],
+                Op::Binary(_, (a, b)) => mask[usize::from(a)] || mask[usize::from(b)],
+            };
+        }
+        GradientMask(mask)
+    }
+
+    /// Like `backward` but only computes the gradients of the nodes in `mask`.
+    /// Branches of the graph that do not lead to a node that requires a
+    /// gradient are skipped entirely and their gradients are left at zero.
+    #[track_caller]
+    pub fn backward_masked(
+        &self,
+        mask: &GradientMask,
+        values: &Values,
+        gradients: &mut Gradients,
+        target: NodeId,
+        gradient: f64,
+    ) {
+        if let Err(error) = self.try_backward_masked(mask, values, gradients, target, gradient) {
+            panic!("{}", error.display(self));
+        }
+    }
+
+    pub fn try_backward_masked(
+        &self,
+        mask: &GradientMask,
+        values: &Values,
+        gradients: &mut Gradients,
+        target: NodeId,
+        gradient: f64,
+    ) -> Result<(), Error> {
+        check_len(self.len(), mask.0.len())?;
+        check_len(self.len(), values.len())?;
+        check_len(self.len(), gradients.len())?;
 
         gradients.fill(0.0);
-        gradients[target] = gradient;
+        let end = self.seed(gradients, [(target, gradient)])?;
+        self.propagate_masked(values, gradients, end, mask);
 
-        for o in self.nodes().rev() {
+        Ok(())
+    }
+
+    /// Propagates the gradients of the nodes before `end` to their inputs in
+    /// reverse order.
+    fn propagate(&self, values: &Values, gradients: &mut Gradients, end: usize) {
+        self.propagate_range(|node| values[node], gradients, 0..end);
+    }
+
+    /// Propagates the gradients of the nodes in `range` to their inputs in
+    /// reverse order, reading node values through `value`.
+    #[inline]
+    pub(crate) fn propagate_range(
+        &self,
+        value: impl Fn(NodeId) -> f64,
+        gradients: &mut Gradients,
+        range: std::ops::Range<usize>,
+    ) {
+        for o in range.rev().map(NodeId::from) {
             let gradients_o = gradients[o];
 
             // If a node's gradient is zero, it can not change it's children and
@@ -419,24 +1023,64 @@ impl Operations {
             }
 
             match self[o] {
-                Op::Nullary(Nullary::Var) => {
+                Op::Nullary(_) => {
                     // Nothing to do.
                 }
                 Op::Unary(unary, i0) => {
-                    gradients[i0] += unary.backward(values[i0], values[o]) * gradients_o;
+                    gradients[i0] += unary.backward(value(i0), value(o)) * gradients_o;
                 }
                 Op::Binary(binary, (i0, i1)) => {
-                    let (gradients_i0, gradients_i1) = binary.backward(values[i0], values[i1], values[o]);
+                    let (gradients_i0, gradients_i1) = binary.backward(value(i0), value(i1), value(o));
                     gradients[i0] += gradients_i0 * gradients_o;
                     gradients[i1] += gradients_i1 * gradients_o;
                 }
             }
         }
     }
+
+    /// Like `propagate` but skips the nodes that are not in `mask`.
+    fn propagate_masked(&self, values: &Values, gradients: &mut Gradients, end: usize, mask: &GradientMask) {
+        for o in (0..end).rev().map(NodeId::from) {
+            let gradients_o = gradients[o];
+
+            if gradients_o == 0.0 || !mask[o] {
+                continue;
+            }
+
+            match self[o] {
+                Op::Nullary(_) => {
+                    // Nothing to do.
+                }
+                Op::Unary(unary, i0) => {
+                    if mask[i0] {
+                        gradients[i0] += unary.backward(values[i0], values[o]) * gradients_o;
+                    }
+                }
+                Op::Binary(binary, (i0, i1)) => {
+                    let (gradients_i0, gradients_i1) = binary.backward(values[i0], values[i1], values[o]);
+                    if mask[i0] {
+                        gradients[i0] += gradients_i0 * gradients_o;
+                    }
+                    if mask[i1] {
+                        gradients[i1] += gradients_i1 * gradients_o;
+                    }
+                }
+            }
+        }
+    }
 }
 
 impl_index_node_id!(Operations, Op);
 
+/// Lets macros like `expr!` accept both an `Operations` and a mutable
+/// reference to one.
+impl AsMut<Operations> for Operations {
+    #[inline]
+    fn as_mut(&mut self) -> &mut Operations {
+        self
+    }
+}
+
 impl_buffer!(Operations, Op);
 
 #[cfg(test)]
@@ -478,6 +1122,43 @@ pub mod tests {
         test_binary_op(Binary::Pow, 81.0, 108.0, 88.9875953821169);
     }
 
+    #[test]
+    fn scale_grad() {
+        test_binary_op(Binary::ScaleGrad, 3.0, 4.0, 0.0);
+    }
+
+    #[test]
+    fn max() {
+        test_binary_op(Binary::Max, 4.0, 0.0, 1.0);
+        assert_eq!(Binary::Max.forward(f64::NEG_INFINITY, 0.0), 0.0);
+        assert_eq!(Binary::Max.backward(2.0, 2.0, 2.0), (1.0, 0.0));
+    }
+
+    fn test_unary_op(op: Unary, va: f64, vb: f64, dbda: f64) {
+        let mut ops = Operations::default();
+        let a = ops.var();
+        let b = ops.insert(Op::Unary(op, a));
+
+        let mut values = Values::new(ops.len());
+        values[a] = va;
+        ops.forward(&mut values);
+        assert_eq!(values[b], vb);
+
+        let mut gradients = Gradients::new(ops.len());
+        ops.backward(&values, &mut gradients, b, 1.0);
+        assert_eq!(gradients[a], dbda);
+    }
+
+    #[test]
+    fn detach() {
+        test_unary_op(Unary::Detach, 2.5, 2.5, 0.0);
+    }
+
+    #[test]
+    fn round_ste() {
+        test_unary_op(Unary::RoundSTE, 2.7, 3.0, 1.0);
+    }
+
     #[test]
     fn node_reuse() {
         // Construct computation graph.
@@ -558,6 +1239,101 @@ pub mod tests {
         );
     }
 
+    #[test]
+    fn backward_many() {
+        let mut ops = Operations::default();
+        let [a, b] = ops.vars();
+        let c = ops.insert(a * b);
+        let d = ops.insert(a + b);
+
+        let mut values = Values::new(ops.len());
+        values[a] = 3.0;
+        values[b] = 4.0;
+        ops.forward(&mut values);
+
+        // Equivalent to the gradient of 2c + 3d.
+        let mut gradients = Gradients::new(ops.len());
+        ops.backward_many(&values, &mut gradients, [(c, 2.0), (d, 3.0)]);
+        assert_eq!(gradients[a], 2.0 * 4.0 + 3.0);
+        assert_eq!(gradients[b], 2.0 * 3.0 + 3.0);
+        assert_eq!(gradients[c], 2.0);
+        assert_eq!(gradients[d], 3.0);
+    }
+
+    #[test]
+    fn backward_accumulate() {
+        let mut ops = Operations::default();
+        let [a, x] = ops.vars();
+        let ax = ops.insert(a * x);
+        let loss = ops.insert(ax.pow_2());
+
+        let mut values = Values::new(ops.len());
+        let mut gradients = Gradients::new(ops.len());
+        let mut expected = Gradients::new(ops.len());
+        let mut scratch = Gradients::new(ops.len());
+        values[a] = 0.5;
+
+        for vx in [1.0, -2.0, 3.0] {
+            values[x] = vx;
+            ops.forward(&mut values);
+            ops.backward_accumulate(&values, &mut gradients, loss, 1.0);
+
+            ops.backward(&values, &mut scratch, loss, 1.0);
+            expected.accumulate(&scratch);
+
+            // Intermediate gradients only hold the gradients of the last pass.
+            assert_eq!(gradients[ax], scratch[ax]);
+        }
+
+        assert_eq!(gradients[a], expected[a]);
+        assert_eq!(gradients[x], expected[x]);
+    }
+
+    #[test]
+    fn backward_masked() {
+        let mut ops = Operations::default();
+        let [a, x, y] = ops.vars();
+        let x2 = ops.insert(x.pow_2());
+        let loss = ops.insert(a * x2 + y);
+
+        let mut values = Values::new(ops.len());
+        values[a] = 3.0;
+        values[x] = 2.0;
+        values[y] = 1.0;
+        ops.forward(&mut values);
+
+        let mask = ops.gradient_mask([a]);
+        assert!(mask[loss]);
+        assert!(!mask[x2]);
+
+        let mut gradients = Gradients::new(ops.len());
+        ops.backward_masked(&mask, &values, &mut gradients, loss, 1.0);
+        assert_eq!(gradients[a], 4.0);
+        assert_eq!(gradients[x], 0.0);
+        assert_eq!(gradients[x2], 0.0);
+        assert_eq!(gradients[y], 0.0);
+    }
+
+    #[test]
+    fn leaf_kinds() {
+        let mut ops = Operations::default();
+        let (x, w) = ops.insert((Input, Parameter));
+        let c = ops.constant(0.5);
+        let b = ops.insert(Parameter);
+        let y = ops.insert(w * x + b * c);
+
+        assert_eq!(ops.parameters().collect::<Vec<_>>(), [w, b]);
+        assert_eq!(ops.inputs().collect::<Vec<_>>(), [x]);
+
+        let mut values = Values::new(ops.len());
+        values[x] = 2.0;
+        values[w] = 3.0;
+        values[b] = 4.0;
+        ops.forward(&mut values);
+        assert_eq!(values[c], 0.5);
+        assert_eq!(values[y], 8.0);
+    }
+
     #[test]
     #[should_panic]
     fn insert_node_from_future() {
@@ -567,4 +1343,81 @@ pub mod tests {
         let mut ops2 = Operations::default();
         let _a2 = ops2.insert(a1); // should panic becasue NodeId(0) doesn't exist in ops2.
     }
+
+    #[test]
+    fn try_insert_node_from_future() {
+        let mut ops1 = Operations::default();
+        let [a1, b1] = ops1.vars();
+
+        let mut ops2 = Operations::default();
+        let a2 = ops2.var();
+        assert_eq!(
+            ops2.try_insert(a2 + b1),
+            Err(Error::NodeOutOfRange { node: b1, len: 1 })
+        );
+        assert_eq!(ops2.len(), 1);
+        assert_eq!(ops2.try_insert(a1 * a2), Ok(NodeId::from(1)));
+
+        // Partially inserted expressions are rolled back.
+        let [.., d1] = ops1.vars::<2>();
+        assert_eq!(
+            ops2.try_insert((a2 * a2) + d1),
+            Err(Error::NodeOutOfRange { node: d1, len: 3 })
+        );
+        assert_eq!(ops2.len(), 2);
+
+        // The same holds for the elements of an array.
+        assert_eq!(
+            ops2.try_insert([a2 * a2, a2 * d1]),
+            Err(Error::NodeOutOfRange { node: d1, len: 3 })
+        );
+        assert_eq!(ops2.len(), 2);
+        assert_eq!(
+            ops2.try_insert([a2 * a2, a1 * a2]),
+            Ok([NodeId::from(2), NodeId::from(3)])
+        );
+
+        // Arrays of any insertable, not only of single nodes.
+        assert_eq!(
+            ops2.try_insert([(a1 * a2, a2), (a2 * a1, a1)]),
+            Ok([(NodeId::from(4), a2), (NodeId::from(5), a1)])
+        );
+        let foreign = NodeId::from(10);
+        assert_eq!(
+            ops2.try_insert([[a2 * a2], [a2 * foreign]]),
+            Err(Error::NodeOutOfRange { node: foreign, len: 7 })
+        );
+        assert_eq!(ops2.len(), 6);
+    }
+
+    #[test]
+    fn try_forward_and_backward_length_mismatch() {
+        let mut ops = Operations::default();
+        let [a, b] = ops.vars();
+        let c = ops.insert(a * b);
+
+        let mut values = Values::new(2);
+        let mut gradients = Gradients::new(ops.len());
+        assert_eq!(
+            ops.try_forward(&mut values),
+            Err(Error::LengthMismatch { expected: 3, actual: 2 })
+        );
+        assert_eq!(
+            ops.try_backward(&values, &mut gradients, c, 1.0),
+            Err(Error::LengthMismatch { expected: 3, actual: 2 })
+        );
+
+        values.resize(ops.len(), 1.0);
+        assert_eq!(
+            ops.try_backward(&values, &mut gradients, NodeId::from(3), 1.0),
+            Err(Error::NodeOutOfRange {
+                node: NodeId::from(3),
+                len: 3
+            })
+        );
+        assert_eq!(
+            gradients.try_accumulate(&Gradients::new(2)),
+            Err(Error::LengthMismatch { expected: 3, actual: 2 })
+        );
+    }
 }
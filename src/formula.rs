@@ -0,0 +1,608 @@
+//! Prints the expression a node computes as math, see `Operations::infix` and
+//! `Operations::latex`.
+use std::fmt;
+
+use crate::engine::{Binary, NodeId, Nullary, Op, Operations, Unary, check_node};
+
+/// Binding strength of the outermost operator of a printed expression.
+type Precedence = u8;
+
+const ADD: Precedence = 1;
+const MUL: Precedence = 2;
+const PREFIX: Precedence = 3;
+const POW: Precedence = 4;
+const ATOM: Precedence = 5;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Notation {
+    /// Plain text like `relu(b + x*w)`, using the `Expr` method names for
+    /// functions.
+    Infix,
+    /// LaTeX math mode like `\operatorname{relu}\left(b + x \cdot w\right)`.
+    LaTeX,
+}
+
+/// Displays the expression computed by a node with as few parentheses as
+/// possible.
+///
+/// Leaves are printed by their name if they have one and as `%index` or
+/// `v_{index}` otherwise. Operations are expanded down to the leaves, except
+/// for operations that are used more than once or that are nested too deeply.
+/// Those are printed once as a binding like `%2 = %0 + %1` before the formula,
+/// one per line, and by their name wherever they are used. Use
+/// `stop_at_names` to also print named operations by their name.
+#[derive(Debug, Copy, Clone)]
+pub struct Formula<'a> {
+    ops: &'a Operations,
+    node: NodeId,
+    notation: Notation,
+    stop_at_names: bool,
+}
+
+impl Formula<'_> {
+    /// Prints named operands by their name instead of expanding them. The node
+    /// the formula is for is always expanded.
+    #[inline]
+    pub fn stop_at_names(self) -> Self {
+        Self {
+            stop_at_names: true,
+            ..self
+        }
+    }
+}
+
+/// Operations nested deeper than this below the node of a formula or a binding
+/// are bound, so that printing recurses a bounded number of levels.
+const MAX_DEPTH: usize = 64;
+
+/// Writes a `Formula`, knowing which operations are printed as bindings.
+struct Writer<'a> {
+    ops: &'a Operations,
+    notation: Notation,
+    stop_at_names: bool,
+    /// Whether each node is printed as a binding, indexed by node.
+    bound: Vec<bool>,
+}
+
+impl<'a> Writer<'a> {
+    fn new(formula: &Formula<'a>) -> Self {
+        let mut writer = Self {
+            ops: formula.ops,
+            notation: formula.notation,
+            stop_at_names: formula.stop_at_names,
+            bound: vec![false; formula.ops.len()],
+        };
+        let root = formula.node;
+
+        // Count how often each node is used by the operations that are
+        // expanded, walking the graph with an explicit stack.
+        let mut uses = vec![0usize; writer.ops.len()];
+        let mut visited = vec![false; writer.ops.len()];
+        let mut stack = vec![root];
+        visited[usize::from(root)] = true;
+        while let Some(node) = stack.pop() {
+            if node != root && writer.is_atom(node) {
+                continue;
+            }
+            for operand in writer.ops[node].operands() {
+                uses[usize::from(operand)] += 1;
+                if !std::mem::replace(&mut visited[usize::from(operand)], true) {
+                    stack.push(operand);
+                }
+            }
+        }
+
+        // Operands are computed before the nodes that use them, so the depth of
+        // each operand is known when its users are visited.
+        let mut depths = vec![0; writer.ops.len()];
+        for node in writer.ops.nodes().filter(|&node| visited[usize::from(node)]) {
+            if node != root && writer.is_atom(node) {
+                continue;
+            }
+            let depth = 1 + writer.ops[node]
+                .operands()
+                .filter(|&operand| !writer.bound[usize::from(operand)])
+                .map(|operand| depths[usize::from(operand)])
+                .max()
+                .unwrap_or(0);
+            depths[usize::from(node)] = depth;
+            writer.bound[usize::from(node)] = node != root && (uses[usize::from(node)] > 1 || depth >= MAX_DEPTH);
+        }
+        writer
+    }
+
+    /// Whether `node` is printed by its name instead of being expanded where
+    /// it is used.
+    #[inline]
+    fn is_atom(&self, node: NodeId) -> bool {
+        matches!(self.ops[node], Op::Nullary(_))
+            || self.bound[usize::from(node)]
+            || self.stop_at_names && self.ops.name(node).is_some()
+    }
+
+    fn precedence(&self, node: NodeId) -> Precedence {
+        let latex = self.notation == Notation::LaTeX;
+        match self.ops[node] {
+            Op::Nullary(Nullary::Constant(constant)) if constant.value().is_sign_negative() => PREFIX,
+            _ if self.is_atom(node) => ATOM,
+            Op::Nullary(_) => ATOM,
+            Op::Unary(unary, _) => match unary {
+                Unary::Neg => PREFIX,
+                Unary::Pow2 => POW,
+                Unary::Recip if latex => ATOM,
+                Unary::Recip => MUL,
+                Unary::Exp2 if latex => POW,
+                Unary::ExpM1 if latex => ADD,
+                _ => ATOM,
+            },
+            Op::Binary(binary, _) => match binary {
+                Binary::Add | Binary::Sub => ADD,
+                Binary::Div if latex => ATOM,
+                Binary::Mul | Binary::Div => MUL,
+                Binary::Pow => POW,
+                Binary::ScaleGrad | Binary::Max => ATOM,
+            },
+        }
+    }
+
+    fn open(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self.notation {
+            Notation::Infix => "(",
+            Notation::LaTeX => r"\left(",
+        })
+    }
+
+    fn close(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self.notation {
+            Notation::Infix => ")",
+            Notation::LaTeX => r"\right)",
+        })
+    }
+
+    fn write_parenthesized(&self, f: &mut fmt::Formatter<'_>, node: NodeId, parenthesize: bool) -> fmt::Result {
+        if parenthesize {
+            self.open(f)?;
+            self.write(f, node)?;
+            self.close(f)
+        } else {
+            self.write(f, node)
+        }
+    }
+
+    /// Writes the left operand of an operator with precedence `precedence`.
+    fn write_left(&self, f: &mut fmt::Formatter<'_>, node: NodeId, precedence: Precedence) -> fmt::Result {
+        self.write_parenthesized(f, node, self.precedence(node) < precedence)
+    }
+
+    /// Writes the right operand of a left associative operator with precedence
+    /// `precedence`. Negations are parenthesized so they never follow another
+    /// operator, as in `a - (-b)`.
+    fn write_right(&self, f: &mut fmt::Formatter<'_>, node: NodeId, precedence: Precedence) -> fmt::Result {
+        let operand = self.precedence(node);
+        self.write_parenthesized(f, node, operand <= precedence || operand == PREFIX)
+    }
+
+    /// Writes `node` as the argument of a function, which is always
+    /// parenthesized.
+    fn write_argument(&self, f: &mut fmt::Formatter<'_>, node: NodeId) -> fmt::Result {
+        self.write_parenthesized(f, node, true)
+    }
+
+    fn write_function(&self, f: &mut fmt::Formatter<'_>, name: &str, a: NodeId) -> fmt::Result {
+        f.write_str(name)?;
+        self.write_argument(f, a)
+    }
+
+    fn write(&self, f: &mut fmt::Formatter<'_>, node: NodeId) -> fmt::Result {
+        if self.is_atom(node) {
+            self.write_leaf(f, node)
+        } else {
+            self.write_expanded(f, node)
+        }
+    }
+
+    /// Writes the operation of `node` even if it is printed by name elsewhere.
+    fn write_expanded(&self, f: &mut fmt::Formatter<'_>, node: NodeId) -> fmt::Result {
+        if matches!(self.ops[node], Op::Nullary(_)) {
+            return self.write_leaf(f, node);
+        }
+        match self.notation {
+            Notation::Infix => self.write_infix(f, self.ops[node]),
+            Notation::LaTeX => self.write_latex(f, self.ops[node]),
+        }
+    }
+
+    fn write_leaf(&self, f: &mut fmt::Formatter<'_>, node: NodeId) -> fmt::Result {
+        if let Op::Nullary(Nullary::Constant(constant)) = self.ops[node] {
+            let value = constant.value();
+            return match self.notation {
+                Notation::Infix => write!(f, "{value}"),
+                Notation::LaTeX if value.is_nan() => f.write_str(r"\mathrm{NaN}"),
+                Notation::LaTeX if value == f64::INFINITY => f.write_str(r"\infty"),
+                Notation::LaTeX if value == f64::NEG_INFINITY => f.write_str(r"-\infty"),
+                Notation::LaTeX => write!(f, "{value}"),
+            };
+        }
+        match (self.notation, self.ops.name(node)) {
+            (Notation::Infix, _) => write!(f, "{}", self.ops.display_node(node)),
+            (Notation::LaTeX, Some(name)) if name.chars().count() == 1 => write_latex_escaped(f, name),
+            (Notation::LaTeX, Some(name)) => {
+                f.write_str(r"\mathit{")?;
+                write_latex_escaped(f, name)?;
+                f.write_str("}")
+            }
+            (Notation::LaTeX, None) => write!(f, "v_{{{}}}", usize::from(node)),
+        }
+    }
+
+    fn write_infix(&self, f: &mut fmt::Formatter<'_>, op: Op) -> fmt::Result {
+        match op {
+            Op::Nullary(_) => unreachable!("leaves are written by write_leaf"),
+            Op::Unary(unary, a) => match unary {
+                Unary::Neg => {
+                    f.write_str("-")?;
+                    self.write_parenthesized(f, a, self.precedence(a) <= PREFIX)
+                }
+                Unary::Pow2 => {
+                    self.write_parenthesized(f, a, self.precedence(a) <= POW)?;
+                    f.write_str("^2")
+                }
+                Unary::Recip => {
+                    f.write_str("1/")?;
+                    self.write_right(f, a, MUL)
+                }
+                _ => self.write_function(f, unary.name(), a),
+            },
+            Op::Binary(binary, (a, b)) => {
+                let (operator, precedence) = match binary {
+                    Binary::Add => (" + ", ADD),
+                    Binary::Sub => (" - ", ADD),
+                    Binary::Mul => ("*", MUL),
+                    Binary::Div => ("/", MUL),
+                    Binary::Pow => {
+                        // Right associative, so `a^b^c` is `a^(b^c)`.
+                        self.write_parenthesized(f, a, self.precedence(a) <= POW)?;
+                        f.write_str("^")?;
+                        return self.write_parenthesized(f, b, self.precedence(b) < POW);
+                    }
+                    Binary::ScaleGrad | Binary::Max => {
+                        f.write_str(binary.name())?;
+                        f.write_str("(")?;
+                        self.write(f, a)?;
+                        f.write_str(", ")?;
+                        self.write(f, b)?;
+                        return f.write_str(")");
+                    }
+                };
+                self.write_left(f, a, precedence)?;
+                f.write_str(operator)?;
+                self.write_right(f, b, precedence)
+            }
+        }
+    }
+
+    fn write_latex(&self, f: &mut fmt::Formatter<'_>, op: Op) -> fmt::Result {
+        match op {
+            Op::Nullary(_) => unreachable!("leaves are written by write_leaf"),
+            Op::Unary(unary, a) => match unary {
+                Unary::Neg => {
+                    f.write_str("-")?;
+                    self.write_parenthesized(f, a, self.precedence(a) <= PREFIX)
+                }
+                Unary::Recip => {
+                    f.write_str(r"\frac{1}{")?;
+                    self.write(f, a)?;
+                    f.write_str("}")
+                }
+                Unary::Pow2 => {
+                    self.write_parenthesized(f, a, self.precedence(a) <= POW)?;
+                    f.write_str("^{2}")
+                }
+                Unary::Ln => self.write_function(f, r"\ln", a),
+                Unary::Ln1P => {
+                    f.write_str(r"\ln\left(1 + ")?;
+                    self.write_right(f, a, ADD)?;
+                    f.write_str(r"\right)")
+                }
+                Unary::Exp => self.write_function(f, r"\exp", a),
+                Unary::Exp2 => {
+                    f.write_str("2^{")?;
+                    self.write(f, a)?;
+                    f.write_str("}")
+                }
+                Unary::ExpM1 => {
+                    self.write_function(f, r"\exp", a)?;
+                    f.write_str(" - 1")
+                }
+                Unary::TanH => self.write_function(f, r"\tanh", a),
+                Unary::ReLU => self.write_function(f, r"\operatorname{relu}", a),
+                Unary::Detach => self.write_function(f, r"\operatorname{detach}", a),
+                Unary::RoundSTE => self.write_function(f, r"\operatorname{round}", a),
+            },
+            Op::Binary(binary, (a, b)) => {
+                let (operator, precedence) = match binary {
+                    Binary::Add => (" + ", ADD),
+                    Binary::Sub => (" - ", ADD),
+                    Binary::Mul => (r" \cdot ", MUL),
+                    Binary::Div => {
+                        f.write_str(r"\frac{")?;
+                        self.write(f, a)?;
+                        f.write_str("}{")?;
+                        self.write(f, b)?;
+                        return f.write_str("}");
+                    }
+                    Binary::Pow => {
+                        self.write_parenthesized(f, a, self.precedence(a) <= POW)?;
+                        f.write_str("^{")?;
+                        self.write(f, b)?;
+                        return f.write_str("}");
+                    }
+                    Binary::ScaleGrad => {
+                        f.write_str(r"\operatorname{scale\_grad}\left(")?;
+                        self.write(f, a)?;
+                        f.write_str(", ")?;
+                        self.write(f, b)?;
+                        return f.write_str(r"\right)");
+                    }
+                    Binary::Max => {
+                        f.write_str(r"\max\left(")?;
+                        self.write(f, a)?;
+                        f.write_str(", ")?;
+                        self.write(f, b)?;
+                        return f.write_str(r"\right)");
+                    }
+                };
+                self.write_left(f, a, precedence)?;
+                f.write_str(operator)?;
+                self.write_right(f, b, precedence)
+            }
+        }
+    }
+}
+
+fn write_latex_escaped(f: &mut fmt::Formatter<'_>, text: &str) -> fmt::Result {
+    for c in text.chars() {
+        match c {
+            '_' | '{' | '}' | '#' | '$' | '%' | '&' => write!(f, r"\{c}")?,
+            '\\' => f.write_str(r"\backslash ")?,
+            '^' => f.write_str(r"\hat{}")?,
+            '~' => f.write_str(r"\sim ")?,
+            c => write!(f, "{c}")?,
+        }
+    }
+    Ok(())
+}
+
+impl fmt::Display for Formula<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let writer = Writer::new(self);
+        let mut bindings = self
+            .ops
+            .nodes()
+            .filter(|&node| writer.bound[usize::from(node)])
+            .peekable();
+        if bindings.peek().is_none() {
+            return writer.write_expanded(f, self.node);
+        }
+        match self.notation {
+            Notation::Infix => {
+                for node in bindings {
+                    writer.write_leaf(f, node)?;
+                    f.write_str(" = ")?;
+                    writer.write_expanded(f, node)?;
+                    f.write_str("\n")?;
+                }
+                writer.write_expanded(f, self.node)
+            }
+            Notation::LaTeX => {
+                f.write_str(r"\begin{aligned}")?;
+                for node in bindings {
+                    writer.write_leaf(f, node)?;
+                    f.write_str(" &= ")?;
+                    writer.write_expanded(f, node)?;
+                    f.write_str(r" \\ ")?;
+                }
+                f.write_str("&")?;
+                writer.write_expanded(f, self.node)?;
+                f.write_str(r"\end{aligned}")
+            }
+        }
+    }
+}
+
+impl Operations {
+    /// Returns the formula computed by `node` as plain text, like
+    /// `relu(bias[0] + input[0,0]*weight[0,0])`.
+    ///
+    /// Panics if `node` is not a node in this graph.
+    #[track_caller]
+    pub fn infix(&self, node: NodeId) -> Formula<'_> {
+        self.formula(node, Notation::Infix)
+    }
+
+    /// Returns the formula computed by `node` as LaTeX math.
+    ///
+    /// Panics if `node` is not a node in this graph.
+    #[track_caller]
+    pub fn latex(&self, node: NodeId) -> Formula<'_> {
+        self.formula(node, Notation::LaTeX)
+    }
+
+    /// Returns the formula computed by `node` in `notation`.
+    ///
+    /// Panics if `node` is not a node in this graph.
+    #[track_caller]
+    pub fn formula(&self, node: NodeId, notation: Notation) -> Formula<'_> {
+        if let Err(error) = check_node(node, self.len()) {
+            panic!("{}", error.display(self));
+        }
+        Formula {
+            ops: self,
+            node,
+            notation,
+            stop_at_names: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nn::{self, FullyConnectedLayer, FullyConnectedLayerParams, MultiLayerPerceptron};
+
+    fn named_vars<const N: usize>(ops: &mut Operations, names: [&str; N]) -> [NodeId; N] {
+        let nodes = ops.vars();
+        for (node, name) in nodes.into_iter().zip(names) {
+            ops.set_name(node, name);
+        }
+        nodes
+    }
+
+    #[test]
+    fn precedence() {
+        let mut ops = Operations::default();
+        ops.record_metadata(true);
+        let [a, b, c] = named_vars(&mut ops, ["a", "b", "c"]);
+        let two = ops.constant(2.0);
+        let minus_one = ops.constant(-1.0);
+
+        let cases = [
+            (ops.insert(a + b * c), "a + b*c", r"a + b \cdot c"),
+            (ops.insert((a + b) * c), "(a + b)*c", r"\left(a + b\right) \cdot c"),
+            (ops.insert(a - b - c), "a - b - c", "a - b - c"),
+            (ops.insert(a - (b - c)), "a - (b - c)", r"a - \left(b - c\right)"),
+            (ops.insert(a / (b * c)), "a/(b*c)", r"\frac{a}{b \cdot c}"),
+            (ops.insert(a.pow(b).pow(c)), "(a^b)^c", r"\left(a^{b}\right)^{c}"),
+            (ops.insert(a.pow(b.pow(c))), "a^b^c", "a^{b^{c}}"),
+            (ops.insert((-a).pow_2()), "(-a)^2", r"\left(-a\right)^{2}"),
+            (ops.insert(-a.pow_2()), "-a^2", "-a^{2}"),
+            (ops.insert(a * -b), "a*(-b)", r"a \cdot \left(-b\right)"),
+            (ops.insert(a * minus_one), "a*(-1)", r"a \cdot \left(-1\right)"),
+            (ops.insert((a + two).recip()), "1/(a + 2)", r"\frac{1}{a + 2}"),
+            (
+                ops.insert(a.ln_1p() + b.exp_m1()),
+                "ln_1p(a) + exp_m1(b)",
+                r"\ln\left(1 + a\right) + \left(\exp\left(b\right) - 1\right)",
+            ),
+            (
+                ops.insert((a * b).exp_2().tanh()),
+                "tanh(exp_2(a*b))",
+                r"\tanh\left(2^{a \cdot b}\right)",
+            ),
+            (
+                ops.insert(a.scale_grad(b).detach()),
+                "detach(scale_grad(a, b))",
+                r"\operatorname{detach}\left(\operatorname{scale\_grad}\left(a, b\right)\right)",
+            ),
+        ];
+        for (node, infix, latex) in cases {
+            assert_eq!(ops.infix(node).to_string(), infix);
+            assert_eq!(ops.latex(node).to_string(), latex);
+        }
+    }
+
+    #[test]
+    fn unnamed_leaves() {
+        let mut ops = Operations::default();
+        let [x, y] = ops.vars();
+        let z = ops.insert((x * y).relu());
+        assert_eq!(ops.infix(z).to_string(), "relu(%0*%1)");
+        assert_eq!(
+            ops.latex(z).to_string(),
+            r"\operatorname{relu}\left(v_{0} \cdot v_{1}\right)"
+        );
+    }
+
+    #[test]
+    fn shared_operations_are_bound() {
+        let mut ops = Operations::default();
+        ops.record_metadata(true);
+        let [x, y] = ops.vars();
+        let s = ops.insert(x + y);
+        ops.set_name(s, "s");
+        let z = ops.insert((x * y).tanh());
+        let out = ops.insert(s * s - z * z);
+
+        assert_eq!(ops.infix(out).to_string(), "s = %0 + %1\n%4 = tanh(%0*%1)\ns*s - %4*%4");
+        assert_eq!(
+            ops.latex(out).to_string(),
+            r"\begin{aligned}s &= v_{0} + v_{1} \\ v_{4} &= \tanh\left(v_{0} \cdot v_{1}\right) \\ &s \cdot s - v_{4} \cdot v_{4}\end{aligned}"
+        );
+        assert_eq!(
+            ops.infix(out).stop_at_names().to_string(),
+            "%4 = tanh(%0*%1)\ns*s - %4*%4"
+        );
+    }
+
+    #[test]
+    fn repeated_doubling_is_linear() {
+        let mut ops = Operations::default();
+        let mut y = ops.var();
+        for _ in 0..100 {
+            y = ops.insert(y + y);
+        }
+        let formula = ops.infix(y).to_string();
+        assert_eq!(formula.lines().count(), 100);
+        assert!(formula.starts_with("%1 = %0 + %0\n%2 = %1 + %1\n"), "{formula}");
+        assert!(formula.ends_with("%99 = %98 + %98\n%99 + %99"), "{formula}");
+        assert!(ops.latex(y).to_string().len() < 100 * 40);
+    }
+
+    #[test]
+    fn deep_chain() {
+        let mut ops = Operations::default();
+        let mut y = ops.var();
+        for _ in 0..100_000 {
+            y = ops.insert(y.tanh());
+        }
+        let formula = ops.infix(y).to_string();
+        assert_eq!(formula.lines().count(), 100_000 / MAX_DEPTH + 1);
+        assert!(formula.lines().all(|line| line.matches("tanh(").count() <= MAX_DEPTH));
+        ops.latex(y).to_string();
+    }
+
+    #[test]
+    fn fully_connected_layer() {
+        // The identity activation inserts no nodes, so the formula does not
+        // depend on where the layer applies its activation.
+        let mut ops = Operations::default();
+        ops.record_metadata(true);
+        let input = nn::input_layer_vec((nn::B(1), nn::O(2)), &mut ops);
+        let layer = FullyConnectedLayer::new(
+            input.as_deref().reindex(nn::batched_output_to_input),
+            nn::O(1),
+            &mut ops,
+            |x| x,
+        );
+        let output = layer.outputs()[(nn::B(0), nn::O(0))];
+
+        assert_eq!(
+            ops.infix(output).to_string(),
+            "bias[0] + input[0,0]*weight[0,0] + input[0,1]*weight[1,0]"
+        );
+        assert_eq!(
+            ops.latex(output).to_string(),
+            r"\mathit{bias[0]} + \mathit{input[0,0]} \cdot \mathit{weight[0,0]} + \mathit{input[0,1]} \cdot \mathit{weight[1,0]}"
+        );
+    }
+
+    #[test]
+    fn stop_at_names() {
+        let mut ops = Operations::default();
+        ops.record_metadata(true);
+        let input = nn::input_layer_vec((nn::B(1), nn::O(1)), &mut ops);
+        let mlp = MultiLayerPerceptron::new(
+            input.as_deref(),
+            &[
+                FullyConnectedLayerParams { output_size: nn::O(1) },
+                FullyConnectedLayerParams { output_size: nn::O(1) },
+            ],
+            &mut ops,
+        );
+        let output = mlp.outputs()[(nn::B(0), nn::O(0))];
+
+        let formula = ops.infix(output).stop_at_names().to_string();
+        assert!(formula.contains("layer1.output[0,0]*layer2.weight[0,0]"), "{formula}");
+        assert!(!formula.contains("layer1.weight"), "{formula}");
+    }
+}
@@ -0,0 +1,192 @@
+use crate::engine::{Error, NodeId, Operations, check_node};
+
+/// A subgraph with declared parameters and outputs that is recorded once and
+/// can then be called many times.
+///
+/// Calls are only inlined: every call copies the body into the calling graph
+/// with the parameters replaced by the arguments. This keeps `Operations` a
+/// flat list of primitive operations so the forward and backward passes do not
+/// need to know about functions, but it does not make graphs smaller. The
+/// calling graph still grows by the size of the body on every call.
+///
+/// Leaves that the body creates itself, other than the parameters, are copied
+/// on every call as well, so each call gets its own. State that the calls
+/// should share, like weights, has to be passed in as arguments.
+#[derive(Debug)]
+pub struct Function {
+    body: Operations,
+    params: Box<[NodeId]>,
+    outputs: Box<[NodeId]>,
+}
+
+impl Function {
+    /// Records a function with `P` parameters. The closure receives a fresh
+    /// graph and the parameter nodes and returns the output nodes.
+    pub fn new<const P: usize, F, R>(f: F) -> Self
+    where
+        F: FnOnce(&mut Operations, [NodeId; P]) -> R,
+        R: IntoIterator<Item = NodeId>,
+    {
+        let mut body = Operations::default();
+        let params = body.vars();
+        let outputs = f(&mut body, params).into_iter().collect();
+        Self {
+            body,
+            params: Box::new(params),
+            outputs,
+        }
+    }
+
+    /// Like `new` but for a number of parameters that is only known at runtime.
+    pub fn with_param_count<F, R>(param_count: usize, f: F) -> Self
+    where
+        F: FnOnce(&mut Operations, &[NodeId]) -> R,
+        R: IntoIterator<Item = NodeId>,
+    {
+        let mut body = Operations::default();
+        let params = body.vars_vec(param_count);
+        let outputs = f(&mut body, &params).into_iter().collect();
+        Self {
+            body,
+            params: params.into_boxed_slice(),
+            outputs,
+        }
+    }
+
+    #[inline]
+    pub fn param_count(&self) -> usize {
+        self.params.len()
+    }
+
+    #[inline]
+    pub fn output_count(&self) -> usize {
+        self.outputs.len()
+    }
+
+    /// The graph that is copied into the caller on every call.
+    #[inline]
+    pub fn body(&self) -> &Operations {
+        &self.body
+    }
+
+    /// Inlines the function into `ops` and returns the output nodes.
+    ///
+    /// Panics if the number of arguments does not match the number of
+    /// parameters, if an argument is not a node in `ops` or if an output
+    /// returned when the function was recorded is not a node of its body.
+    #[track_caller]
+    pub fn call(&self, ops: &mut Operations, args: &[NodeId]) -> Vec<NodeId> {
+        match self.try_call(ops, args) {
+            Ok(outputs) => outputs,
+            // The nodes of the error may belong to the body or to `ops`, so
+            // they are not looked up by name.
+            Err(error) => panic!("{error}"),
+        }
+    }
+
+    pub fn try_call(&self, ops: &mut Operations, args: &[NodeId]) -> Result<Vec<NodeId>, Error> {
+        if args.len() != self.params.len() {
+            return Err(Error::ArgumentCountMismatch {
+                expected: self.params.len(),
+                actual: args.len(),
+            });
+        }
+
+        for &output in &self.outputs {
+            check_node(output, self.body.len())?;
+        }
+
+        let remap = ops.try_append_with(
+            &self.body,
+            std::iter::zip(self.params.iter().copied(), args.iter().copied()),
+        )?;
+        Ok(self.outputs.iter().map(|&output| remap[output]).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{Gradients, Values};
+
+    #[test]
+    fn call_twice() {
+        let affine = Function::new(|ops, [a, x, b]| [ops.insert(a * x + b)]);
+        assert_eq!(affine.param_count(), 3);
+        assert_eq!(affine.output_count(), 1);
+
+        let mut ops = Operations::default();
+        let [a, b, x0, x1] = ops.vars();
+        let [y0] = affine.call(&mut ops, &[a, x0, b])[..] else {
+            unreachable!()
+        };
+        let [y1] = affine.call(&mut ops, &[a, x1, b])[..] else {
+            unreachable!()
+        };
+        let loss = ops.insert(y0 + y1);
+
+        let mut values = Values::new(ops.len());
+        values[a] = 2.0;
+        values[b] = 3.0;
+        values[x0] = 5.0;
+        values[x1] = 7.0;
+        ops.forward(&mut values);
+        assert_eq!(values[y0], 13.0);
+        assert_eq!(values[y1], 17.0);
+
+        let mut gradients = Gradients::new(ops.len());
+        ops.backward(&values, &mut gradients, loss, 1.0);
+        assert_eq!(gradients[a], 12.0);
+        assert_eq!(gradients[b], 2.0);
+    }
+
+    #[test]
+    fn with_param_count() {
+        let sum = Function::with_param_count(3, |ops, params| {
+            let sum = params[1..]
+                .iter()
+                .fold(params[0], |sum, &param| ops.insert(sum + param));
+            [sum]
+        });
+
+        let mut ops = Operations::default();
+        let xs = ops.vars_vec(3);
+        let [y] = sum.call(&mut ops, &xs)[..] else {
+            unreachable!()
+        };
+
+        let mut values = Values::new(ops.len());
+        values[xs[0]] = 1.0;
+        values[xs[1]] = 2.0;
+        values[xs[2]] = 3.0;
+        ops.forward(&mut values);
+        assert_eq!(values[y], 6.0);
+    }
+
+    #[test]
+    fn try_call_argument_count_mismatch() {
+        let square = Function::new(|ops, [x]| [ops.insert(x.pow_2())]);
+
+        let mut ops = Operations::default();
+        let [a, b] = ops.vars();
+        assert_eq!(
+            square.try_call(&mut ops, &[a, b]),
+            Err(Error::ArgumentCountMismatch { expected: 1, actual: 2 })
+        );
+        assert_eq!(ops.len(), 2);
+    }
+
+    #[test]
+    fn try_call_foreign_output() {
+        let foreign = NodeId::from(10);
+        let broken = Function::new(|ops, [x]| [ops.insert(x.pow_2()), foreign]);
+
+        let mut ops = Operations::default();
+        let a = ops.var();
+        assert_eq!(
+            broken.try_call(&mut ops, &[a]),
+            Err(Error::NodeOutOfRange { node: foreign, len: 2 })
+        );
+        assert_eq!(ops.len(), 1);
+    }
+}
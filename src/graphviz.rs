@@ -1,6 +1,6 @@
 use std::io::Write;
 
-use crate::engine::{Binary, NodeId, Op, Operations, Unary};
+use crate::engine::{Binary, NodeId, Nullary, Op, Operations, Unary};
 
 pub fn export_to_dot<'l, W: Write, L: Fn(NodeId) -> &'l str, R: Fn(NodeId) -> Option<usize>>(
     ops: &Operations,
@@ -14,8 +14,8 @@ pub fn export_to_dot<'l, W: Write, L: Fn(NodeId) -> &'l str, R: Fn(NodeId) -> Op
 
     let should_emit_value_node = |node: NodeId| -> bool {
         match ops[node] {
-            Op::Nullary(crate::engine::Nullary::Var) => true, // Always emit variables
-            _ => !labels(node).is_empty(),                    // Only emit value nodes if they have a label
+            Op::Nullary(_) => true,        // Always emit leaves
+            _ => !labels(node).is_empty(), // Only emit value nodes if they have a label
         }
     };
 
@@ -32,9 +32,15 @@ pub fn export_to_dot<'l, W: Write, L: Fn(NodeId) -> &'l str, R: Fn(NodeId) -> Op
     for node in ops.nodes() {
         if should_emit_value_node(node) {
             let index = usize::from(node);
-            let label = labels(node);
+            let label = match (labels(node), ops[node]) {
+                ("", Op::Nullary(Nullary::Constant(constant))) => constant.value().to_string(),
+                (label, _) => label.to_string(),
+            };
             let fillcolor = match ops[node] {
-                Op::Nullary(crate::engine::Nullary::Var) => "lightblue",
+                Op::Nullary(Nullary::Var) => "lightblue",
+                Op::Nullary(Nullary::Input) => "lightcyan",
+                Op::Nullary(Nullary::Parameter) => "plum",
+                Op::Nullary(Nullary::Constant(_)) => "lightgrey",
                 _ => "lightyellow",
             };
 
@@ -50,21 +56,31 @@ pub fn export_to_dot<'l, W: Write, L: Fn(NodeId) -> &'l str, R: Fn(NodeId) -> Op
         let index = usize::from(node);
 
         match ops[node] {
-            Op::Nullary(crate::engine::Nullary::Var) => {
+            Op::Nullary(_) => {
                 // Nothing to do.
             }
             Op::Unary(unary_op, _) => {
                 let label = unary_op_to_str(unary_op);
+                let (shape, fillcolor) = if modifies_gradient(ops[node]) {
+                    ("octagon", "lightsalmon")
+                } else {
+                    ("diamond", "lightgreen")
+                };
                 writeln!(
                     writer,
-                    "    op{index} [label=\"{label}\", shape=diamond, regular=true, fillcolor=lightgreen, width=0.5, height=0.5, fixedsize=true];"
+                    "    op{index} [label=\"{label}\", shape={shape}, regular=true, fillcolor={fillcolor}, width=0.5, height=0.5, fixedsize=true];"
                 )?;
             }
             Op::Binary(binary_op, _) => {
                 let label = binary_op_to_str(binary_op);
+                let (shape, fillcolor) = if modifies_gradient(ops[node]) {
+                    ("octagon", "lightsalmon")
+                } else {
+                    ("diamond", "lightgreen")
+                };
                 writeln!(
                     writer,
-                    "    op{index} [label=\"{label}\", shape=diamond, regular=true, fillcolor=lightgreen, width=0.5, height=0.5, fixedsize=true];"
+                    "    op{index} [label=\"{label}\", shape={shape}, regular=true, fillcolor={fillcolor}, width=0.5, height=0.5, fixedsize=true];"
                 )?;
             }
         }
@@ -86,7 +102,7 @@ pub fn export_to_dot<'l, W: Write, L: Fn(NodeId) -> &'l str, R: Fn(NodeId) -> Op
         let index = usize::from(node);
 
         match ops[node] {
-            Op::Nullary(crate::engine::Nullary::Var) => {
+            Op::Nullary(_) => {
                 // Nothing to do.
             }
             Op::Unary(_, input) => {
@@ -114,6 +130,34 @@ pub fn export_to_dot<'l, W: Write, L: Fn(NodeId) -> &'l str, R: Fn(NodeId) -> Op
     Ok(())
 }
 
+/// Operations whose gradient differs from the derivative of their forward
+/// computation are drawn differently so they stand out.
+fn modifies_gradient(op: Op) -> bool {
+    matches!(
+        op,
+        Op::Unary(Unary::Detach | Unary::RoundSTE, _) | Op::Binary(Binary::ScaleGrad, _)
+    )
+}
+
+/// Like `export_to_dot` but labels nodes with their names and places nodes with
+/// the same group at the same rank, see `Operations::record_metadata`.
+pub fn export_to_dot_with_metadata<W: Write>(ops: &Operations, writer: &mut W) -> std::io::Result<()> {
+    let mut group_ranks = std::collections::HashMap::<&str, usize>::new();
+    for node in ops.nodes() {
+        if let Some(group) = ops.group(node) {
+            let rank = group_ranks.len();
+            group_ranks.entry(group).or_insert(rank);
+        }
+    }
+
+    export_to_dot(
+        ops,
+        |node| ops.name(node).unwrap_or_default(),
+        |node| ops.group(node).map(|group| group_ranks[group]),
+        writer,
+    )
+}
+
 fn binary_op_to_str(op: Binary) -> &'static str {
     match op {
         Binary::Add => "+",
@@ -121,6 +165,8 @@ fn binary_op_to_str(op: Binary) -> &'static str {
         Binary::Mul => "*",
         Binary::Div => "/",
         Binary::Pow => "^",
+        Binary::ScaleGrad => "scale∇",
+        Binary::Max => "max",
     }
 }
 
@@ -136,5 +182,7 @@ fn unary_op_to_str(op: Unary) -> &'static str {
         Unary::ExpM1 => "exp(x)-1",
         Unary::TanH => "tanh(x)",
         Unary::ReLU => "ReLU(x)",
+        Unary::Detach => "detach",
+        Unary::RoundSTE => "round (STE)",
     }
 }
@@ -0,0 +1,341 @@
+//! A human readable textual representation of `Operations`.
+//!
+//! Every node is printed on its own line as `%<id> = <op> <operands>`,
+//! optionally followed by the quoted name of the node:
+//!
+//! ```text
+//! %0 = input "x"
+//! %1 = parameter "w"
+//! %2 = constant 0.5
+//! %3 = mul %1, %0
+//! %4 = add %3, %2 "y"
+//! ```
+//!
+//! Operations are named like the `Expr` methods that build them, see
+//! `Op::name`. The parser accepts any identifier after the `%` so hand written
+//! graphs can use `%x` instead of `%0`, as well as blank lines and comments
+//! starting with `//`.
+use std::{collections::HashMap, fmt, str::FromStr};
+
+use crate::engine::{Binary, Constant, NodeId, Nullary, Op, Operations, Unary};
+
+impl fmt::Display for Operations {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for node in self.nodes() {
+            let op = self[node];
+            write!(f, "%{} = {}", usize::from(node), op.name())?;
+            match op {
+                Op::Nullary(Nullary::Constant(constant)) => write!(f, " {:?}", constant.value())?,
+                Op::Nullary(_) => {}
+                Op::Unary(_, a) => write!(f, " %{}", usize::from(a))?,
+                Op::Binary(_, (a, b)) => write!(f, " %{}, %{}", usize::from(a), usize::from(b))?,
+            }
+            if let Some(name) = self.name(node) {
+                write!(f, " {name:?}")?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// An error in the textual representation, with the 1-based line it occurred
+/// on.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ParseError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl FromStr for Operations {
+    type Err = ParseError;
+
+    /// Parses the textual representation. Node names are recorded as metadata
+    /// if any node has one.
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let mut ops = Operations::default();
+        let mut ids = HashMap::<&str, NodeId>::new();
+
+        for (index, line) in text.lines().enumerate() {
+            let error = |message: String| ParseError {
+                line: index + 1,
+                message,
+            };
+
+            let mut cursor = Cursor(line);
+            if cursor.at_end() {
+                continue;
+            }
+
+            let id = cursor.id().map_err(error)?;
+            cursor.expect('=').map_err(error)?;
+            let op = cursor.op(&ids).map_err(error)?;
+            let name = cursor.name().map_err(error)?;
+            if !cursor.at_end() {
+                return Err(error(format!("unexpected `{}`", cursor.0)));
+            }
+
+            let node = ops.try_insert(op).map_err(|e| error(e.to_string()))?;
+            if ids.insert(id, node).is_some() {
+                return Err(error(format!("%{id} is defined more than once")));
+            }
+            if let Some(name) = name {
+                ops.record_metadata(true);
+                ops.set_name(node, name);
+            }
+        }
+
+        ops.debug_assert_valid_from(0);
+        Ok(ops)
+    }
+}
+
+/// The remainder of a line that is being parsed.
+struct Cursor<'a>(&'a str);
+
+impl<'a> Cursor<'a> {
+    fn skip_whitespace(&mut self) {
+        self.0 = self.0.trim_start();
+    }
+
+    /// Whether only whitespace and a comment remain. Comments are recognized
+    /// here rather than before parsing so that names may contain `//`.
+    fn at_end(&mut self) -> bool {
+        self.skip_whitespace();
+        self.0.is_empty() || self.0.starts_with("//")
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        self.skip_whitespace();
+        match self.0.strip_prefix(c) {
+            Some(rest) => {
+                self.0 = rest;
+                Ok(())
+            }
+            None => Err(format!("expected `{c}` but found `{}`", self.0)),
+        }
+    }
+
+    fn word(&mut self) -> &'a str {
+        self.skip_whitespace();
+        let end = self
+            .0
+            .find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '.' || c == '-' || c == '+'))
+            .unwrap_or(self.0.len());
+        let (word, rest) = self.0.split_at(end);
+        self.0 = rest;
+        word
+    }
+
+    fn id(&mut self) -> Result<&'a str, String> {
+        self.expect('%')?;
+        match self.word() {
+            "" => Err("expected a node id after `%`".to_string()),
+            id => Ok(id),
+        }
+    }
+
+    fn operand(&mut self, ids: &HashMap<&str, NodeId>) -> Result<NodeId, String> {
+        let id = self.id()?;
+        ids.get(id).copied().ok_or_else(|| format!("%{id} is not defined"))
+    }
+
+    fn op(&mut self, ids: &HashMap<&str, NodeId>) -> Result<Op, String> {
+        let name = self.word();
+        let op = match name {
+            "var" => Op::Nullary(Nullary::Var),
+            "input" => Op::Nullary(Nullary::Input),
+            "parameter" => Op::Nullary(Nullary::Parameter),
+            "constant" => {
+                let value = self.word();
+                let value = value
+                    .parse::<f64>()
+                    .map_err(|_| format!("expected a number but found `{value}`"))?;
+                Op::Nullary(Nullary::Constant(Constant::new(value)))
+            }
+            _ => {
+                if let Some(unary) = Unary::ALL.into_iter().find(|unary| unary.name() == name) {
+                    Op::Unary(unary, self.operand(ids)?)
+                } else if let Some(binary) = Binary::ALL.into_iter().find(|binary| binary.name() == name) {
+                    let a = self.operand(ids)?;
+                    self.expect(',')?;
+                    Op::Binary(binary, (a, self.operand(ids)?))
+                } else {
+                    return Err(format!("unknown operation `{name}`"));
+                }
+            }
+        };
+        Ok(op)
+    }
+
+    /// Parses an optional quoted name with the escapes produced by `{:?}`.
+    fn name(&mut self) -> Result<Option<String>, String> {
+        self.skip_whitespace();
+        let Some(rest) = self.0.strip_prefix('"') else {
+            return Ok(None);
+        };
+        let mut name = String::new();
+        let mut chars = rest.char_indices();
+        while let Some((index, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.0 = rest[index + 1..].trim_start();
+                    return Ok(Some(name));
+                }
+                '\\' => match chars.next().map(|(_, c)| c) {
+                    Some('n') => name.push('\n'),
+                    Some('r') => name.push('\r'),
+                    Some('t') => name.push('\t'),
+                    Some('0') => name.push('\0'),
+                    Some(c @ ('\\' | '"' | '\'')) => name.push(c),
+                    Some('u') => {
+                        let rest = &rest[index + 2..];
+                        let code = rest
+                            .strip_prefix('{')
+                            .and_then(|rest| rest.split_once('}'))
+                            .and_then(|(hex, _)| u32::from_str_radix(hex, 16).ok())
+                            .and_then(char::from_u32)
+                            .ok_or("invalid unicode escape")?;
+                        name.push(code);
+                        for (_, c) in chars.by_ref() {
+                            if c == '}' {
+                                break;
+                            }
+                        }
+                    }
+                    _ => return Err("invalid escape in name".to_string()),
+                },
+                c => name.push(c),
+            }
+        }
+        Err("unterminated name".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        engine::{Input, Parameter, Values},
+        nn::{self, FullyConnectedLayer},
+    };
+
+    #[test]
+    fn print() {
+        let mut ops = Operations::default();
+        ops.record_metadata(true);
+        let (x, w) = ops.insert((Input, Parameter));
+        ops.set_name(x, "x");
+        let c = ops.constant(0.5);
+        let y = ops.insert((w * x + c).relu());
+        ops.set_name(y, "y \"out\"");
+
+        assert_eq!(
+            ops.to_string(),
+            r#"%0 = input "x"
+%1 = parameter
+%2 = constant 0.5
+%3 = mul %1, %0
+%4 = add %3, %2
+%5 = relu %4 "y \"out\""
+"#
+        );
+    }
+
+    #[test]
+    fn fully_connected_layer_golden() {
+        // The identity activation inserts no nodes, so the golden does not
+        // depend on where the layer applies its activation.
+        let mut ops = Operations::default();
+        let input = nn::input_layer_vec((nn::B(1), nn::O(2)), &mut ops);
+        FullyConnectedLayer::new(
+            input.as_deref().reindex(nn::batched_output_to_input),
+            nn::O(1),
+            &mut ops,
+            |x| x,
+        );
+
+        assert_eq!(
+            ops.to_string(),
+            "\
+%0 = input
+%1 = input
+%2 = parameter
+%3 = parameter
+%4 = parameter
+%5 = mul %0, %2
+%6 = add %4, %5
+%7 = mul %1, %3
+%8 = add %6, %7
+"
+        );
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut ops = Operations::default();
+        ops.record_metadata(true);
+        ops.push_scope("model");
+        let [a, b] = ops.vars();
+        ops.set_name(a, "weight[0,1]");
+        ops.set_name(b, "http://x");
+        let c = ops.constant(-1.0e-3);
+        ops.insert((a.pow(b) / c).exp_m1().scale_grad(a).round_ste());
+        ops.set_name(c, "tab\tnewline\n");
+
+        let text = ops.to_string();
+        let parsed = text.parse::<Operations>().unwrap();
+        assert_eq!(parsed.iter().collect::<Vec<_>>(), ops.iter().collect::<Vec<_>>());
+        assert_eq!(parsed.name(a), Some("model.weight[0,1]"));
+        assert_eq!(parsed.name(b), Some("model.http://x"));
+        assert_eq!(parsed.name(c), Some("model.tab\tnewline\n"));
+        assert_eq!(parsed.to_string(), text);
+    }
+
+    #[test]
+    fn parse_hand_written() {
+        let ops = "
+            // y = w * x + 1
+            %x = input \"x\" // a \"sample\"
+            %w = parameter // trailing comment
+            %one = constant 1
+            %wx = mul %w, %x
+            %y = add %wx, %one
+        "
+        .parse::<Operations>()
+        .unwrap();
+
+        let mut values = Values::new(ops.len());
+        values[NodeId::from(0)] = 3.0;
+        values[NodeId::from(1)] = 2.0;
+        ops.forward(&mut values);
+        assert_eq!(values[NodeId::from(4)], 7.0);
+        assert_eq!(ops.name(NodeId::from(0)), Some("x"));
+    }
+
+    #[test]
+    fn parse_errors() {
+        let error = "%a = var\n%b = mul %a, %c".parse::<Operations>().unwrap_err();
+        assert_eq!(
+            error,
+            ParseError {
+                line: 2,
+                message: "%c is not defined".to_string()
+            }
+        );
+
+        let error = "%a = sqrt %a".parse::<Operations>().unwrap_err();
+        assert_eq!(error.message, "unknown operation `sqrt`");
+
+        let error = "%a = var\n%a = var".parse::<Operations>().unwrap_err();
+        assert_eq!(error.to_string(), "line 2: %a is defined more than once");
+    }
+}
@@ -1,8 +1,27 @@
+pub mod checkpoint;
 pub mod deref_slice;
 pub mod engine;
+pub mod formula;
+pub mod function;
 pub mod graphviz;
+pub mod ir;
+pub mod metadata;
 pub mod nn;
+pub mod parser;
+pub mod planner;
+pub mod reduce;
+pub mod remap;
+pub mod rewrite;
+pub mod scalar;
+#[cfg(feature = "serde")]
+pub mod serialization;
+pub mod stats;
+pub mod structure;
 pub mod syntax;
+pub mod tensor;
+pub mod validate;
+pub mod value;
 pub mod view;
 
 pub mod iter_ext;
+mod macros;
@@ -0,0 +1,350 @@
+/// Inserts a formula into an `Operations` and returns the node of its result.
+///
+/// ```
+/// use micrograd_rs::{engine::Operations, expr};
+///
+/// let mut ops = Operations::default();
+/// let [a, b, x] = ops.vars();
+/// let y = expr!(ops, a * x^2 + tanh(b) / 3.0);
+/// ```
+///
+/// The first argument is an `Operations` or a mutable reference to one. The
+/// formula supports:
+///
+/// - `+`, `-`, `*` and `/` with the usual precedence and left associativity,
+/// - `^` for `pow`, which binds tighter than negation and is right
+///   associative, so `-x^2^3` is `-(x^(2^3))`. `x^2` inserts a `pow_2`,
+/// - function calls for every `Unary` operation, named like the `Expr`
+///   methods, as well as `pow(a, b)`, `scale_grad(a, b)` and `max(a, b)`,
+/// - numeric literals, which are inserted as constants,
+/// - identifiers of local `NodeId`s and `{ ... }` blocks that evaluate to a
+///   `NodeId`.
+///
+/// Every operation is inserted with a separate `Operations::insert` call, from
+/// left to right. The formula is parsed one token at a time, so very long
+/// formulas may need a higher `recursion_limit`.
+///
+/// Malformed formulas are rejected at compile time. Unknown functions:
+///
+/// ```compile_fail
+/// use micrograd_rs::{engine::Operations, expr};
+///
+/// let mut ops = Operations::default();
+/// let [a] = ops.vars();
+/// expr!(ops, foo(a));
+/// ```
+///
+/// Calls with the wrong number of arguments:
+///
+/// ```compile_fail
+/// use micrograd_rs::{engine::Operations, expr};
+///
+/// let mut ops = Operations::default();
+/// let [a, b] = ops.vars();
+/// expr!(ops, tanh(a, b));
+/// ```
+///
+/// ```compile_fail
+/// use micrograd_rs::{engine::Operations, expr};
+///
+/// let mut ops = Operations::default();
+/// let [a] = ops.vars();
+/// expr!(ops, pow(a));
+/// ```
+///
+/// And operators without a right operand:
+///
+/// ```compile_fail
+/// use micrograd_rs::{engine::Operations, expr};
+///
+/// let mut ops = Operations::default();
+/// let [a] = ops.vars();
+/// expr!(ops, a +);
+/// ```
+#[macro_export]
+macro_rules! expr {
+    // Sums: split the tokens into terms at every `+` or `-` that follows an
+    // operand. The flag tells whether the next token has to be an operand,
+    // which makes a `-` unary.
+    (@sum $ops:ident; [$($done:tt)*] $sign:tt [$($cur:tt)+] operator + $($rest:tt)*) => {
+        $crate::expr!(@sum $ops; [$($done)* ($sign [$($cur)+])] + [] operand $($rest)*)
+    };
+    (@sum $ops:ident; [$($done:tt)*] $sign:tt [$($cur:tt)+] operator - $($rest:tt)*) => {
+        $crate::expr!(@sum $ops; [$($done)* ($sign [$($cur)+])] - [] operand $($rest)*)
+    };
+    (@sum $ops:ident; $done:tt $sign:tt [$($cur:tt)*] operand - $($rest:tt)*) => {
+        $crate::expr!(@sum $ops; $done $sign [$($cur)* -] operand $($rest)*)
+    };
+    (@sum $ops:ident; $done:tt $sign:tt $cur:tt operand + $($rest:tt)*) => {
+        compile_error!("expr!: expected an operand before `+`")
+    };
+    (@sum $ops:ident; $done:tt $sign:tt $cur:tt $flag:ident , $($rest:tt)*) => {
+        compile_error!("expr!: unexpected `,`, only `pow`, `scale_grad` and `max` take two arguments")
+    };
+    (@sum $ops:ident; $done:tt $sign:tt [$($cur:tt)*] $flag:ident * $($rest:tt)*) => {
+        $crate::expr!(@sum $ops; $done $sign [$($cur)* *] operand $($rest)*)
+    };
+    (@sum $ops:ident; $done:tt $sign:tt [$($cur:tt)*] $flag:ident / $($rest:tt)*) => {
+        $crate::expr!(@sum $ops; $done $sign [$($cur)* /] operand $($rest)*)
+    };
+    (@sum $ops:ident; $done:tt $sign:tt [$($cur:tt)*] $flag:ident ^ $($rest:tt)*) => {
+        $crate::expr!(@sum $ops; $done $sign [$($cur)* ^] operand $($rest)*)
+    };
+    (@sum $ops:ident; $done:tt $sign:tt [$($cur:tt)*] $flag:ident $token:tt $($rest:tt)*) => {
+        $crate::expr!(@sum $ops; $done $sign [$($cur)* $token] operator $($rest)*)
+    };
+    (@sum $ops:ident; [$($done:tt)*] $sign:tt [$($cur:tt)+] operator) => {
+        $crate::expr!(@sum_fold $ops; $($done)* ($sign [$($cur)+]))
+    };
+    (@sum $ops:ident; $done:tt $sign:tt $cur:tt operand) => {
+        compile_error!("expr!: expected an operand at the end of the formula")
+    };
+
+    (@sum_fold $ops:ident; (+ [$($first:tt)+]) $($rest:tt)*) => {
+        $crate::expr!(@sum_fold $ops; { $crate::expr!(@product $ops; [] * [] $($first)+) } $($rest)*)
+    };
+    (@sum_fold $ops:ident; $acc:block ($sign:tt [$($term:tt)+]) $($rest:tt)*) => {
+        $crate::expr!(@sum_fold $ops; {
+            let lhs = $acc;
+            let rhs = $crate::expr!(@product $ops; [] * [] $($term)+);
+            $ops.insert(lhs $sign rhs)
+        } $($rest)*)
+    };
+    (@sum_fold $ops:ident; $acc:block) => {
+        $acc
+    };
+
+    // Products: split a term into factors at every `*` and `/`.
+    (@product $ops:ident; [$($done:tt)*] $op:tt [$($cur:tt)+] * $($rest:tt)*) => {
+        $crate::expr!(@product $ops; [$($done)* ($op [$($cur)+])] * [] $($rest)*)
+    };
+    (@product $ops:ident; [$($done:tt)*] $op:tt [$($cur:tt)+] / $($rest:tt)*) => {
+        $crate::expr!(@product $ops; [$($done)* ($op [$($cur)+])] / [] $($rest)*)
+    };
+    (@product $ops:ident; $done:tt $op:tt [] * $($rest:tt)*) => {
+        compile_error!("expr!: expected an operand before `*`")
+    };
+    (@product $ops:ident; $done:tt $op:tt [] / $($rest:tt)*) => {
+        compile_error!("expr!: expected an operand before `/`")
+    };
+    (@product $ops:ident; $done:tt $op:tt [$($cur:tt)*] $token:tt $($rest:tt)*) => {
+        $crate::expr!(@product $ops; $done $op [$($cur)* $token] $($rest)*)
+    };
+    (@product $ops:ident; [$($done:tt)*] $op:tt [$($cur:tt)+]) => {
+        $crate::expr!(@product_fold $ops; $($done)* ($op [$($cur)+]))
+    };
+
+    (@product_fold $ops:ident; (* [$($first:tt)+]) $($rest:tt)*) => {
+        $crate::expr!(@product_fold $ops; { $crate::expr!(@factor $ops; $($first)+) } $($rest)*)
+    };
+    (@product_fold $ops:ident; $acc:block ($op:tt [$($factor:tt)+]) $($rest:tt)*) => {
+        $crate::expr!(@product_fold $ops; {
+            let lhs = $acc;
+            let rhs = $crate::expr!(@factor $ops; $($factor)+);
+            $ops.insert(lhs $op rhs)
+        } $($rest)*)
+    };
+    (@product_fold $ops:ident; $acc:block) => {
+        $acc
+    };
+
+    // Factors: negations and powers of a single operand.
+    (@factor $ops:ident; - $value:literal) => {
+        $crate::expr!(@constant $ops; -$value)
+    };
+    (@factor $ops:ident; - $($rest:tt)+) => {{
+        let a = $crate::expr!(@factor $ops; $($rest)+);
+        $ops.insert(-a)
+    }};
+    (@factor $ops:ident; $f:ident ($($args:tt)*) ^ 2) => {{
+        let a = $crate::expr!(@call $ops; $f; $($args)*);
+        $ops.insert(a.pow_2())
+    }};
+    (@factor $ops:ident; $f:ident ($($args:tt)*) ^ $($exponent:tt)+) => {{
+        let a = $crate::expr!(@call $ops; $f; $($args)*);
+        let b = $crate::expr!(@factor $ops; $($exponent)+);
+        $ops.insert(a.pow(b))
+    }};
+    (@factor $ops:ident; $f:ident ($($args:tt)*)) => {
+        $crate::expr!(@call $ops; $f; $($args)*)
+    };
+    (@factor $ops:ident; $base:tt ^ 2) => {{
+        let a = $crate::expr!(@base $ops; $base);
+        $ops.insert(a.pow_2())
+    }};
+    (@factor $ops:ident; $base:tt ^ $($exponent:tt)+) => {{
+        let a = $crate::expr!(@base $ops; $base);
+        let b = $crate::expr!(@factor $ops; $($exponent)+);
+        $ops.insert(a.pow(b))
+    }};
+    (@factor $ops:ident; $base:tt) => {
+        $crate::expr!(@base $ops; $base)
+    };
+    (@factor $ops:ident; $base:tt $($rest:tt)+) => {
+        compile_error!(concat!(
+            "expr!: expected an operator between `",
+            stringify!($base),
+            "` and `",
+            stringify!($($rest)+),
+            "`"
+        ))
+    };
+
+    (@base $ops:ident; ($($inner:tt)+)) => {
+        $crate::expr!(@sum $ops; [] + [] operand $($inner)+)
+    };
+    (@base $ops:ident; { $($block:tt)+ }) => {{
+        let node: $crate::engine::NodeId = { $($block)+ };
+        node
+    }};
+    (@base $ops:ident; $value:literal) => {
+        $crate::expr!(@constant $ops; $value)
+    };
+    (@base $ops:ident; $node:ident) => {{
+        let node: $crate::engine::NodeId = $node;
+        node
+    }};
+    (@base $ops:ident; $token:tt) => {
+        compile_error!(concat!("expr!: unexpected `", stringify!($token), "`"))
+    };
+
+    (@constant $ops:ident; $($value:tt)+) => {{
+        #[allow(clippy::unnecessary_cast)]
+        let value = $($value)+ as f64;
+        $ops.constant(value)
+    }};
+
+    // Function calls.
+    (@call $ops:ident; $f:ident;) => {
+        compile_error!(concat!("expr!: `", stringify!($f), "` expects an argument"))
+    };
+    (@call $ops:ident; pow; $($args:tt)+) => {
+        $crate::expr!(@binary_call $ops; pow; [] $($args)+)
+    };
+    (@call $ops:ident; scale_grad; $($args:tt)+) => {
+        $crate::expr!(@binary_call $ops; scale_grad; [] $($args)+)
+    };
+    (@call $ops:ident; max; $($args:tt)+) => {
+        $crate::expr!(@binary_call $ops; max; [] $($args)+)
+    };
+    (@call $ops:ident; neg; $($arg:tt)+) => {{
+        let a = $crate::expr!(@sum $ops; [] + [] operand $($arg)+);
+        $ops.insert(-a)
+    }};
+    (@call $ops:ident; recip; $($arg:tt)+) => { $crate::expr!(@unary_call $ops; recip; $($arg)+) };
+    (@call $ops:ident; pow_2; $($arg:tt)+) => { $crate::expr!(@unary_call $ops; pow_2; $($arg)+) };
+    (@call $ops:ident; ln; $($arg:tt)+) => { $crate::expr!(@unary_call $ops; ln; $($arg)+) };
+    (@call $ops:ident; ln_1p; $($arg:tt)+) => { $crate::expr!(@unary_call $ops; ln_1p; $($arg)+) };
+    (@call $ops:ident; exp; $($arg:tt)+) => { $crate::expr!(@unary_call $ops; exp; $($arg)+) };
+    (@call $ops:ident; exp_2; $($arg:tt)+) => { $crate::expr!(@unary_call $ops; exp_2; $($arg)+) };
+    (@call $ops:ident; exp_m1; $($arg:tt)+) => { $crate::expr!(@unary_call $ops; exp_m1; $($arg)+) };
+    (@call $ops:ident; tanh; $($arg:tt)+) => { $crate::expr!(@unary_call $ops; tanh; $($arg)+) };
+    (@call $ops:ident; relu; $($arg:tt)+) => { $crate::expr!(@unary_call $ops; relu; $($arg)+) };
+    (@call $ops:ident; detach; $($arg:tt)+) => { $crate::expr!(@unary_call $ops; detach; $($arg)+) };
+    (@call $ops:ident; round_ste; $($arg:tt)+) => { $crate::expr!(@unary_call $ops; round_ste; $($arg)+) };
+    (@call $ops:ident; $f:ident; $($args:tt)*) => {
+        compile_error!(concat!(
+            "expr!: unknown function `",
+            stringify!($f),
+            "`, expected one of neg, recip, pow_2, ln, ln_1p, exp, exp_2, exp_m1, tanh, relu, detach, round_ste, pow, scale_grad or max"
+        ))
+    };
+
+    (@unary_call $ops:ident; $f:ident; $($arg:tt)+) => {{
+        let a = $crate::expr!(@sum $ops; [] + [] operand $($arg)+);
+        $ops.insert(a.$f())
+    }};
+
+    (@binary_call $ops:ident; $f:ident; [$($a:tt)*] , $($b:tt)+) => {{
+        let a = $crate::expr!(@sum $ops; [] + [] operand $($a)*);
+        let b = $crate::expr!(@sum $ops; [] + [] operand $($b)+);
+        $ops.insert(a.$f(b))
+    }};
+    (@binary_call $ops:ident; $f:ident; [$($a:tt)*] $token:tt $($rest:tt)*) => {
+        $crate::expr!(@binary_call $ops; $f; [$($a)* $token] $($rest)*)
+    };
+    (@binary_call $ops:ident; $f:ident; [$($a:tt)*]) => {
+        compile_error!(concat!("expr!: `", stringify!($f), "` expects two arguments"))
+    };
+
+    ($ops:expr, $($formula:tt)+) => {{
+        let ops: &mut $crate::engine::Operations = $ops.as_mut();
+        $crate::expr!(@sum ops; [] + [] operand $($formula)+)
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::{NodeId, Operations, Values};
+
+    fn eval(ops: &Operations, vars: &[(NodeId, f64)], node: NodeId) -> f64 {
+        let mut values = Values::new(ops.len());
+        for &(var, value) in vars {
+            values[var] = value;
+        }
+        ops.forward(&mut values);
+        values[node]
+    }
+
+    #[test]
+    fn precedence() {
+        let mut ops = Operations::default();
+        let [a, b, x] = ops.vars();
+        let vars = [(a, 2.0), (b, 0.5), (x, 3.0)];
+
+        let cases = [
+            (expr!(ops, a * x ^ 2 + tanh(b) / 3.0), 2.0 * 9.0 + 0.5f64.tanh() / 3.0),
+            (expr!(ops, a - b - x), 2.0 - 0.5 - 3.0),
+            (expr!(ops, a - (b - x)), 2.0 - (0.5 - 3.0)),
+            (expr!(ops, a / b * x), 2.0 / 0.5 * 3.0),
+            (expr!(ops, -x ^ 2), -9.0),
+            (expr!(ops, (-x) ^ 2), 9.0),
+            (expr!(ops, a ^ b ^ 2), 2.0f64.powf(0.25)),
+            (expr!(ops, a * -b + -2), 2.0 * -0.5 - 2.0),
+            (expr!(ops, 1 - -x), 4.0),
+            (expr!(ops, x ^ -1), 1.0 / 3.0),
+            (expr!(ops, exp(a) ^ 2), 2.0f64.exp().powi(2)),
+        ];
+        for (node, expected) in cases {
+            assert_eq!(eval(&ops, &vars, node), expected, "{}", ops.infix(node));
+        }
+    }
+
+    #[test]
+    fn functions() {
+        let mut ops = Operations::default();
+        let [a, b] = ops.vars();
+        let vars = [(a, 0.25), (b, 1.5)];
+        let nodes = [a, b];
+
+        let cases = [
+            (expr!(ops, neg(a + b)), -1.75),
+            (expr!(ops, recip(b)), 1.0 / 1.5),
+            (expr!(ops, pow_2(b)), 2.25),
+            (expr!(ops, ln(b)), 1.5f64.ln()),
+            (expr!(ops, ln_1p(a)), 0.25f64.ln_1p()),
+            (expr!(ops, exp(a)), 0.25f64.exp()),
+            (expr!(ops, exp_2(b)), 1.5f64.exp2()),
+            (expr!(ops, exp_m1(a)), 0.25f64.exp_m1()),
+            (expr!(ops, relu(a - b)), 0.0),
+            (expr!(ops, detach(a) * 2), 0.5),
+            (expr!(ops, round_ste(b * 3)), 5.0),
+            (expr!(ops, pow(b, a + 1)), 1.5f64.powf(1.25)),
+            (expr!(ops, scale_grad(a, 0.5)), 0.25),
+            (expr!(ops, { nodes[1] } * 2.0), 3.0),
+        ];
+        for (node, expected) in cases {
+            assert_eq!(eval(&ops, &vars, node), expected, "{}", ops.infix(node));
+        }
+    }
+
+    #[test]
+    fn mutable_reference_and_pow_2() {
+        let mut ops = Operations::default();
+        let ops = &mut ops;
+        let x = ops.var();
+        let y = expr!(ops, x ^ 2);
+        assert_eq!(ops.infix(y).to_string(), "%0^2");
+        assert_eq!(ops.len(), 2);
+    }
+}
@@ -0,0 +1,311 @@
+use std::{fmt::Display, panic::Location};
+
+use crate::engine::{NodeId, Operations};
+
+/// Optional information about a node that is only used for diagnostics.
+#[derive(Debug, Default, Clone)]
+pub struct NodeInfo {
+    /// Hierarchical name like `layer1.weight[3,2]`.
+    pub name: Option<String>,
+    /// Nodes with the same group are drawn together by graphviz.
+    pub group: Option<String>,
+    /// The source location of the `Operations::insert` call that created the
+    /// node.
+    pub location: Option<&'static Location<'static>>,
+}
+
+/// Side table storing `NodeInfo` for the nodes of an `Operations`.
+///
+/// Recording is disabled by default so that building large graphs does not pay
+/// for formatting names it never uses. While disabled, the setters below do
+/// nothing.
+#[derive(Debug, Default)]
+pub struct Metadata {
+    enabled: bool,
+    nodes: Vec<NodeInfo>,
+    scopes: Vec<String>,
+    caller: Option<&'static Location<'static>>,
+}
+
+impl Metadata {
+    /// Called by `Operations` whenever a node is pushed.
+    pub(crate) fn on_insert(&mut self, node: NodeId) {
+        if let Some(location) = self.caller {
+            self.info_mut(node).location = Some(location);
+        }
+    }
+
+    pub(crate) fn truncate(&mut self, len: usize) {
+        self.nodes.truncate(len);
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.nodes.clear();
+    }
+
+    /// Records `location` as the location of all nodes inserted while `f` runs,
+    /// unless an enclosing call already did so.
+    pub(crate) fn with_caller<R>(
+        ops: &mut Operations,
+        location: &'static Location<'static>,
+        f: impl FnOnce(&mut Operations) -> R,
+    ) -> R {
+        let outermost = ops.1.enabled && ops.1.caller.is_none();
+        if outermost {
+            ops.1.caller = Some(location);
+        }
+        let output = f(ops);
+        if outermost {
+            ops.1.caller = None;
+        }
+        output
+    }
+
+    fn info_mut(&mut self, node: NodeId) -> &mut NodeInfo {
+        let index = usize::from(node);
+        if index >= self.nodes.len() {
+            self.nodes.resize_with(index + 1, Default::default);
+        }
+        &mut self.nodes[index]
+    }
+
+    fn scoped(&self, name: impl Display) -> String {
+        let mut path = String::new();
+        for scope in &self.scopes {
+            path.push_str(scope);
+            path.push('.');
+        }
+        path.push_str(&name.to_string());
+        path
+    }
+}
+
+impl Operations {
+    /// Enables or disables recording node names, groups and insert locations.
+    #[inline]
+    pub fn record_metadata(&mut self, enabled: bool) {
+        self.1.enabled = enabled;
+    }
+
+    #[inline]
+    pub fn is_recording_metadata(&self) -> bool {
+        self.1.enabled
+    }
+
+    /// Prefixes the names and groups set until the matching `pop_scope` with
+    /// `scope` followed by a dot.
+    #[inline]
+    pub fn push_scope(&mut self, scope: impl Display) {
+        if self.1.enabled {
+            self.1.scopes.push(scope.to_string());
+        }
+    }
+
+    #[inline]
+    pub fn pop_scope(&mut self) {
+        if self.1.enabled {
+            self.1.scopes.pop();
+        }
+    }
+
+    #[inline]
+    pub fn set_name(&mut self, node: NodeId, name: impl Display) {
+        if self.1.enabled {
+            let name = self.1.scoped(name);
+            self.1.info_mut(node).name = Some(name);
+        }
+    }
+
+    #[inline]
+    pub fn set_group(&mut self, node: NodeId, group: impl Display) {
+        if self.1.enabled {
+            let group = self.1.scoped(group);
+            self.1.info_mut(node).group = Some(group);
+        }
+    }
+
+    #[inline]
+    pub fn info(&self, node: NodeId) -> Option<&NodeInfo> {
+        self.1.nodes.get(usize::from(node))
+    }
+
+    #[inline]
+    pub fn name(&self, node: NodeId) -> Option<&str> {
+        self.info(node)?.name.as_deref()
+    }
+
+    #[inline]
+    pub fn group(&self, node: NodeId) -> Option<&str> {
+        self.info(node)?.group.as_deref()
+    }
+
+    #[inline]
+    pub fn location(&self, node: NodeId) -> Option<&'static Location<'static>> {
+        self.info(node)?.location
+    }
+
+    /// Copies the name and group of `source` in `other` to `target`, below the
+    /// current scope, and its insert location. Used when nodes are copied
+    /// between graphs.
+    pub(crate) fn copy_metadata(&mut self, target: NodeId, other: &Operations, source: NodeId) {
+        if !self.1.enabled {
+            return;
+        }
+        let Some(info) = other.info(source) else {
+            return;
+        };
+        if let Some(name) = &info.name {
+            self.set_name(target, name);
+        }
+        if let Some(group) = &info.group {
+            self.set_group(target, group);
+        }
+        if let Some(location) = info.location {
+            self.1.info_mut(target).location = Some(location);
+        }
+    }
+
+    /// Returns something that displays the name of `node` if it has one and
+    /// its index otherwise, for use in diagnostics.
+    #[inline]
+    pub fn display_node(&self, node: NodeId) -> impl Display + '_ {
+        NodeDisplay { ops: Some(self), node }
+    }
+}
+
+/// Displays a node like `Operations::display_node`, or by its index when there
+/// is no graph to look its name up in.
+pub(crate) struct NodeDisplay<'a> {
+    pub(crate) ops: Option<&'a Operations>,
+    pub(crate) node: NodeId,
+}
+
+impl Display for NodeDisplay<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.ops.and_then(|ops| ops.name(self.node)) {
+            Some(name) => f.write_str(name),
+            None => write!(f, "%{}", usize::from(self.node)),
+        }
+    }
+}
+
+/// Diagnostics that mention nodes, which are displayed by name when the graph
+/// is known.
+pub(crate) trait DisplayWithNames {
+    fn fmt_with_names(&self, f: &mut std::fmt::Formatter<'_>, ops: Option<&Operations>) -> std::fmt::Result;
+}
+
+pub(crate) struct WithNames<'a, T> {
+    pub(crate) value: &'a T,
+    pub(crate) ops: Option<&'a Operations>,
+}
+
+impl<T: DisplayWithNames> Display for WithNames<'_, T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.value.fmt_with_names(f, self.ops)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_and_locations() {
+        let mut ops = Operations::default();
+        ops.record_metadata(true);
+        ops.push_scope("model");
+        let [a, b] = ops.vars();
+        ops.set_name(a, "a");
+        ops.set_group(a, "inputs");
+        ops.pop_scope();
+        let line = line!() + 1;
+        let c = ops.insert(a * b + a);
+
+        assert_eq!(ops.name(a), Some("model.a"));
+        assert_eq!(ops.group(a), Some("model.inputs"));
+        assert_eq!(ops.display_node(a).to_string(), "model.a");
+        assert_eq!(ops.display_node(b).to_string(), "%1");
+
+        let location = ops.location(c).unwrap();
+        assert_eq!(location.file(), file!());
+        assert_eq!(location.line(), line);
+        assert_eq!(ops.location(NodeId::from(2)), Some(location));
+    }
+
+    #[test]
+    fn extend_records_the_caller() {
+        let mut ops = Operations::default();
+        ops.record_metadata(true);
+        let line = line!() + 1;
+        let nodes: Vec<_> = ops.extend([crate::engine::Var; 2]).collect();
+        for node in nodes {
+            let location = ops.location(node).unwrap();
+            assert_eq!((location.file(), location.line()), (file!(), line));
+        }
+    }
+
+    #[test]
+    fn diagnostics_show_names() {
+        use crate::{engine::Error, validate::Problem};
+
+        let mut ops = Operations::default();
+        ops.record_metadata(true);
+        let [a, b] = ops.vars();
+        ops.set_name(a, "a");
+
+        let problem = Problem::UnusedNode { node: a };
+        assert_eq!(problem.display(&ops).to_string(), "node a is never used");
+        assert_eq!(problem.to_string(), "node %0 is never used");
+        let error = Error::ForwardReference { node: a, operand: b };
+        assert_eq!(
+            error.display(&ops).to_string(),
+            "node a cannot read node %1 because it is not computed before it"
+        );
+    }
+
+    #[test]
+    fn multi_layer_perceptron_names() {
+        use crate::nn::{self, FullyConnectedLayerParams, MultiLayerPerceptron};
+
+        let mut ops = Operations::default();
+        ops.record_metadata(true);
+        let input = nn::input_layer_vec((nn::B(1), nn::O(2)), &mut ops);
+        ops.push_scope("mlp");
+        let mlp = MultiLayerPerceptron::new(
+            input.as_deref(),
+            &[
+                FullyConnectedLayerParams { output_size: nn::O(3) },
+                FullyConnectedLayerParams { output_size: nn::O(1) },
+            ],
+            &mut ops,
+        );
+        ops.pop_scope();
+
+        assert_eq!(ops.name(input[(nn::B(0), nn::O(1))]), Some("input[0,1]"));
+        assert_eq!(
+            ops.name(mlp.layers[0].weights()[(nn::I(0), nn::O(1))]),
+            Some("mlp.layer1.weight[0,1]")
+        );
+        let layer = &mlp.layers[0];
+        assert_eq!(
+            ops.name(layer.weights()[(nn::I(1), nn::O(2))]),
+            Some("mlp.layer1.weight[1,2]")
+        );
+        assert_eq!(ops.name(layer.biases()[(nn::O(2),)]), Some("mlp.layer1.bias[2]"));
+        assert_eq!(ops.group(layer.biases()[(nn::O(2),)]), Some("mlp.layer1.parameters"));
+        assert_eq!(
+            ops.name(mlp.outputs()[(nn::B(0), nn::O(0))]),
+            Some("mlp.layer2.output[0,0]")
+        );
+    }
+
+    #[test]
+    fn disabled_by_default() {
+        let mut ops = Operations::default();
+        let a = ops.var();
+        ops.set_name(a, "a");
+        assert_eq!(ops.name(a), None);
+        assert_eq!(ops.location(a), None);
+    }
+}
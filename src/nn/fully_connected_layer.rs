@@ -36,7 +36,7 @@ impl FullyConnectedLayer {
         let bias_offset = weight_dims.product();
         let output_offset = bias_offset + bias_dims.product();
 
-        let mut vars = ops.vars_vec(output_offset);
+        let mut vars = ops.parameter_vec(output_offset);
 
         let (init, mut spare) = vars.reserve_split_spare((batch_size, output_size).product());
         let weights = View::new(&init[0..bias_offset], weight_dims);
@@ -50,11 +50,42 @@ impl FullyConnectedLayer {
             })
         }));
 
-        Self {
+        let layer = Self {
             batch_size,
             input_size,
             output_size,
             vars: vars.into_boxed_slice(),
+        };
+        layer.set_names(ops);
+        layer
+    }
+
+    /// Names the parameters and outputs of this layer in the current scope of
+    /// `ops`, if it records metadata.
+    fn set_names(&self, ops: &mut Operations) {
+        if !ops.is_recording_metadata() {
+            return;
+        }
+        // The names follow the indices of the views. `iter_enumerate` is not
+        // used because it pairs elements with indices in a different order
+        // than `IndexTuple::flatten` stores them.
+        let weights = self.weights();
+        for (i, o) in weights.shape().indices() {
+            let node = weights[(i, o)];
+            ops.set_name(node, format_args!("weight[{},{}]", usize::from(i), usize::from(o)));
+            ops.set_group(node, "parameters");
+        }
+        let biases = self.biases();
+        for (o,) in biases.shape().indices() {
+            let node = biases[(o,)];
+            ops.set_name(node, format_args!("bias[{}]", usize::from(o)));
+            ops.set_group(node, "parameters");
+        }
+        let outputs = self.outputs();
+        for (b, o) in outputs.shape().indices() {
+            let node = outputs[(b, o)];
+            ops.set_name(node, format_args!("output[{},{}]", usize::from(b), usize::from(o)));
+            ops.set_group(node, "outputs");
         }
     }
 
@@ -101,12 +132,11 @@ impl FullyConnectedLayer {
         View::new(&self.vars[range], (self.batch_size, self.output_size))
     }
 
+    /// The weights followed by the biases, which are the nodes allocated by
+    /// [`Operations::parameter_vec`] when the layer was built.
     #[inline]
     pub fn parameters(&self) -> impl Iterator<Item = NodeId> {
-        self.weights()
-            .into_iter()
-            .copied()
-            .chain(self.biases().into_iter().copied())
+        self.vars[..self.output_offset()].iter().copied()
     }
 
     #[inline]
@@ -132,6 +162,48 @@ impl FullyConnectedLayer {
 }
 
 pub fn input_layer_vec(len: (B, O), ops: &mut Operations) -> View<Vec<NodeId>, (B, O)> {
-    let data = ops.vars_vec(len.product());
-    View::new(data, len)
+    let data = ops.input_vec(len.product());
+    let inputs = View::new(data, len);
+    if ops.is_recording_metadata() {
+        for (b, o) in len.indices() {
+            let node = inputs[(b, o)];
+            ops.set_name(node, format_args!("input[{},{}]", usize::from(b), usize::from(o)));
+            ops.set_group(node, "inputs");
+        }
+    }
+    inputs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The weight connecting input `i` to output `o` is parameter
+    /// `o * input_size + i`, see `IndexTuple`. Saved parameter files depend on
+    /// this.
+    #[test]
+    fn weight_layout() {
+        let mut ops = Operations::default();
+        let inputs = input_layer_vec((B(1), O(2)), &mut ops);
+        let layer = FullyConnectedLayer::new(
+            inputs.as_deref().reindex(batched_output_to_input),
+            O(3),
+            &mut ops,
+            |x| x,
+        );
+
+        let mut values = Values::new(ops.len());
+        values[inputs[(B(0), O(0))]] = 1.0;
+        values[inputs[(B(0), O(1))]] = 10.0;
+        for (flat, &weight) in layer.weights().iter().enumerate() {
+            values[weight] = flat as f64;
+        }
+        for &bias in layer.biases().iter() {
+            values[bias] = 0.0;
+        }
+        ops.forward(&mut values);
+
+        let outputs: Vec<_> = layer.outputs().iter().map(|&node| values[node]).collect();
+        assert_eq!(outputs, [10.0, 32.0, 54.0]);
+    }
 }
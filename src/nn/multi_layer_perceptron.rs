@@ -34,12 +34,15 @@ impl MultiLayerPerceptron {
                 let prev_output = layers
                     .last()
                     .map_or(input_layer, |layer: &FullyConnectedLayer| layer.outputs());
+                // Layer 0 is the input layer.
+                ops.push_scope(format_args!("layer{}", layers.len() + 1));
                 let layer = FullyConnectedLayer::new(
                     prev_output.as_deref().reindex(nn::batched_output_to_input),
                     params.output_size,
                     ops,
                     Expr::relu,
                 );
+                ops.pop_scope();
                 layers.push(layer);
                 layers
             })
@@ -71,12 +74,27 @@ impl MultiLayerPerceptron {
 
     #[inline]
     pub fn parameters(&self) -> impl Iterator<Item = NodeId> {
-        self.layers.iter().flat_map(|layer| {
-            layer
-                .weights()
-                .into_iter()
-                .copied()
-                .chain(layer.biases().into_iter().copied())
-        })
+        self.layers.iter().flat_map(FullyConnectedLayer::parameters)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nn::{B, input_layer_vec};
+
+    #[test]
+    fn parameters_are_the_parameter_leaves() {
+        let mut ops = Operations::default();
+        let inputs = input_layer_vec((B(2), nn::O(3)), &mut ops);
+        let layer_params = [
+            FullyConnectedLayerParams { output_size: nn::O(4) },
+            FullyConnectedLayerParams { output_size: nn::O(1) },
+        ];
+        let mlp = MultiLayerPerceptron::new(inputs.as_deref(), &layer_params, &mut ops);
+
+        let parameters: Vec<_> = mlp.parameters().collect();
+        assert_eq!(parameters.len(), 3 * 4 + 4 + 4 + 1);
+        assert_eq!(parameters, ops.parameters().collect::<Vec<_>>());
     }
 }
@@ -0,0 +1,507 @@
+//! Parses formulas like `ln_1p(exp(a*x)) - y` from strings at runtime, with
+//! the same syntax as the `expr!` macro.
+use std::{collections::HashMap, fmt, ops::Range};
+
+use crate::{
+    engine::{Binary, Constant, NodeId, Nullary, Op, Operations, Unary, Var, check_node},
+    rewrite::Mark,
+};
+
+/// An error in a formula, along with the byte range of the offending text.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct FormulaError {
+    pub span: Range<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for FormulaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at {}..{}", self.message, self.span.start, self.span.end)
+    }
+}
+
+impl std::error::Error for FormulaError {}
+
+/// Inserts formulas into an `Operations`, looking up names in a symbol table.
+///
+/// Supported are `+`, `-`, `*`, `/` and `^` with the precedence of the `expr!`
+/// macro, numbers, names and calls of every `Unary` operation as well as
+/// `pow(a, b)`, `scale_grad(a, b)` and `max(a, b)`. Functions are named like the `Expr`
+/// methods, optionally without underscores, so both `ln_1p` and `ln1p` work.
+#[derive(Debug)]
+pub struct FormulaParser<'s> {
+    symbols: &'s mut HashMap<String, NodeId>,
+    create_vars: bool,
+}
+
+impl<'s> FormulaParser<'s> {
+    #[inline]
+    pub fn new(symbols: &'s mut HashMap<String, NodeId>) -> Self {
+        Self {
+            symbols,
+            create_vars: false,
+        }
+    }
+
+    /// Inserts a new variable for every name that is not in the symbol table
+    /// and adds it to the table, instead of reporting an error.
+    #[inline]
+    pub fn create_vars(self, create_vars: bool) -> Self {
+        Self { create_vars, ..self }
+    }
+
+    /// Inserts the nodes for `text` into `ops` and returns the node of the
+    /// result. On error, the nodes and symbols added so far are removed again.
+    pub fn parse(&mut self, ops: &mut Operations, text: &str) -> Result<NodeId, FormulaError> {
+        let mark = ops.mark();
+        let mut parser = Parser {
+            ops,
+            symbols: self.symbols,
+            create_vars: self.create_vars,
+            created: Vec::new(),
+            tokens: tokenize(text)?,
+            position: 0,
+        };
+        let result = parser.formula();
+        if result.is_err() {
+            parser.roll_back(mark);
+        }
+        result
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum TokenKind<'t> {
+    Number(f64),
+    Name(&'t str),
+    Plus,
+    Minus,
+    Star,
+    Slash,
+    Caret,
+    Comma,
+    Open,
+    Close,
+    End,
+}
+
+impl fmt::Display for TokenKind<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TokenKind::Number(value) => write!(f, "`{value}`"),
+            TokenKind::Name(name) => write!(f, "`{name}`"),
+            TokenKind::Plus => f.write_str("`+`"),
+            TokenKind::Minus => f.write_str("`-`"),
+            TokenKind::Star => f.write_str("`*`"),
+            TokenKind::Slash => f.write_str("`/`"),
+            TokenKind::Caret => f.write_str("`^`"),
+            TokenKind::Comma => f.write_str("`,`"),
+            TokenKind::Open => f.write_str("`(`"),
+            TokenKind::Close => f.write_str("`)`"),
+            TokenKind::End => f.write_str("the end of the formula"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Token<'t> {
+    kind: TokenKind<'t>,
+    span: Range<usize>,
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token<'_>>, FormulaError> {
+    let mut tokens = Vec::new();
+    let mut chars = text.char_indices().peekable();
+    while let Some(&(start, c)) = chars.peek() {
+        let mut end = start + c.len_utf8();
+        let kind = match c {
+            c if c.is_whitespace() => {
+                chars.next();
+                continue;
+            }
+            '0'..='9' | '.' => {
+                // Digits, a fraction and an exponent with an optional sign.
+                let mut previous = c;
+                chars.next();
+                while let Some(&(index, c)) = chars.peek() {
+                    let is_exponent_sign = matches!(c, '+' | '-') && matches!(previous, 'e' | 'E');
+                    if !(c.is_ascii_alphanumeric() || c == '.' || is_exponent_sign) {
+                        break;
+                    }
+                    previous = c;
+                    end = index + c.len_utf8();
+                    chars.next();
+                }
+                let number = &text[start..end];
+                match number.parse() {
+                    Ok(value) => TokenKind::Number(value),
+                    Err(_) => {
+                        return Err(FormulaError {
+                            span: start..end,
+                            message: format!("invalid number `{number}`"),
+                        });
+                    }
+                }
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                chars.next();
+                while let Some(&(index, c)) = chars.peek() {
+                    if !(c.is_alphanumeric() || c == '_') {
+                        break;
+                    }
+                    end = index + c.len_utf8();
+                    chars.next();
+                }
+                TokenKind::Name(&text[start..end])
+            }
+            _ => {
+                chars.next();
+                match c {
+                    '+' => TokenKind::Plus,
+                    '-' => TokenKind::Minus,
+                    '*' => TokenKind::Star,
+                    '/' => TokenKind::Slash,
+                    '^' => TokenKind::Caret,
+                    ',' => TokenKind::Comma,
+                    '(' => TokenKind::Open,
+                    ')' => TokenKind::Close,
+                    _ => {
+                        return Err(FormulaError {
+                            span: start..end,
+                            message: format!("unexpected character `{c}`"),
+                        });
+                    }
+                }
+            }
+        };
+        tokens.push(Token { kind, span: start..end });
+    }
+    tokens.push(Token {
+        kind: TokenKind::End,
+        span: text.len()..text.len(),
+    });
+    Ok(tokens)
+}
+
+enum Function {
+    Unary(Unary),
+    Binary(Binary),
+}
+
+/// Looks up a function by its `Expr` method name, ignoring underscores.
+fn function(name: &str) -> Option<Function> {
+    let matches = |candidate: &str| candidate.replace('_', "") == name.replace('_', "");
+    if let Some(unary) = Unary::ALL.into_iter().find(|unary| matches(unary.name())) {
+        return Some(Function::Unary(unary));
+    }
+    [Binary::Pow, Binary::ScaleGrad, Binary::Max]
+        .into_iter()
+        .find(|binary| matches(binary.name()))
+        .map(Function::Binary)
+}
+
+struct Parser<'a, 't> {
+    ops: &'a mut Operations,
+    symbols: &'a mut HashMap<String, NodeId>,
+    create_vars: bool,
+    created: Vec<String>,
+    tokens: Vec<Token<'t>>,
+    position: usize,
+}
+
+impl<'t> Parser<'_, 't> {
+    fn roll_back(&mut self, mark: Mark) {
+        for name in self.created.drain(..) {
+            self.symbols.remove(&name);
+        }
+        self.ops.truncate_to(mark);
+    }
+
+    #[inline]
+    fn peek(&self) -> TokenKind<'t> {
+        self.tokens[self.position].kind
+    }
+
+    #[inline]
+    fn peek_second(&self) -> TokenKind<'t> {
+        self.tokens
+            .get(self.position + 1)
+            .map_or(TokenKind::End, |token| token.kind)
+    }
+
+    #[inline]
+    fn next(&mut self) -> Token<'t> {
+        let token = self.tokens[self.position].clone();
+        if token.kind != TokenKind::End {
+            self.position += 1;
+        }
+        token
+    }
+
+    fn unexpected(&self, expected: &str) -> FormulaError {
+        let token = &self.tokens[self.position];
+        FormulaError {
+            span: token.span.clone(),
+            message: format!("expected {expected} but found {}", token.kind),
+        }
+    }
+
+    fn expect(&mut self, kind: TokenKind<'t>, expected: &str) -> Result<(), FormulaError> {
+        if self.peek() == kind {
+            self.next();
+            Ok(())
+        } else {
+            Err(self.unexpected(expected))
+        }
+    }
+
+    fn insert(&mut self, op: Op) -> NodeId {
+        self.ops.insert(op)
+    }
+
+    fn formula(&mut self) -> Result<NodeId, FormulaError> {
+        let node = self.sum()?;
+        self.expect(TokenKind::End, "an operator")?;
+        Ok(node)
+    }
+
+    fn sum(&mut self) -> Result<NodeId, FormulaError> {
+        let mut lhs = self.product()?;
+        loop {
+            let binary = match self.peek() {
+                TokenKind::Plus => Binary::Add,
+                TokenKind::Minus => Binary::Sub,
+                _ => return Ok(lhs),
+            };
+            self.next();
+            let rhs = self.product()?;
+            lhs = self.insert(Op::Binary(binary, (lhs, rhs)));
+        }
+    }
+
+    fn product(&mut self) -> Result<NodeId, FormulaError> {
+        let mut lhs = self.factor()?;
+        loop {
+            let binary = match self.peek() {
+                TokenKind::Star => Binary::Mul,
+                TokenKind::Slash => Binary::Div,
+                _ => return Ok(lhs),
+            };
+            self.next();
+            let rhs = self.factor()?;
+            lhs = self.insert(Op::Binary(binary, (lhs, rhs)));
+        }
+    }
+
+    /// Negations and powers. The exponent of `^` is itself a factor, which
+    /// makes `^` right associative and bind tighter than negation.
+    fn factor(&mut self) -> Result<NodeId, FormulaError> {
+        if self.peek() == TokenKind::Minus {
+            self.next();
+            if let TokenKind::Number(value) = self.peek()
+                && self.peek_second() != TokenKind::Caret
+            {
+                self.next();
+                return Ok(self.insert(Op::Nullary(Nullary::Constant(Constant::new(-value)))));
+            }
+            let a = self.factor()?;
+            return Ok(self.insert(Op::Unary(Unary::Neg, a)));
+        }
+
+        let base = self.operand()?;
+        if self.peek() != TokenKind::Caret {
+            return Ok(base);
+        }
+        self.next();
+        if self.peek() == TokenKind::Number(2.0) && self.peek_second() != TokenKind::Caret {
+            self.next();
+            return Ok(self.insert(Op::Unary(Unary::Pow2, base)));
+        }
+        let exponent = self.factor()?;
+        Ok(self.insert(Op::Binary(Binary::Pow, (base, exponent))))
+    }
+
+    fn operand(&mut self) -> Result<NodeId, FormulaError> {
+        let token = self.next();
+        match token.kind {
+            TokenKind::Number(value) => Ok(self.insert(Op::Nullary(Nullary::Constant(Constant::new(value))))),
+            TokenKind::Open => {
+                let node = self.sum()?;
+                self.expect(TokenKind::Close, "`)`")?;
+                Ok(node)
+            }
+            TokenKind::Name(name) if self.peek() == TokenKind::Open => self.call(name, token.span),
+            TokenKind::Name(name) => self.symbol(name, token.span),
+            _ => {
+                self.position -= 1;
+                Err(self.unexpected("a number, name or `(`"))
+            }
+        }
+    }
+
+    fn symbol(&mut self, name: &str, span: Range<usize>) -> Result<NodeId, FormulaError> {
+        if let Some(&node) = self.symbols.get(name) {
+            return match check_node(node, self.ops.len()) {
+                Ok(()) => Ok(node),
+                Err(error) => Err(FormulaError {
+                    span,
+                    message: format!("`{name}` refers to an invalid node: {error}"),
+                }),
+            };
+        }
+        if !self.create_vars {
+            return Err(FormulaError {
+                span,
+                message: format!("unknown name `{name}`"),
+            });
+        }
+        let node = self.ops.insert(Var);
+        self.symbols.insert(name.to_string(), node);
+        self.created.push(name.to_string());
+        Ok(node)
+    }
+
+    fn call(&mut self, name: &str, span: Range<usize>) -> Result<NodeId, FormulaError> {
+        let function = function(name).ok_or_else(|| FormulaError {
+            span: span.clone(),
+            message: format!("unknown function `{name}`"),
+        })?;
+        self.next();
+        let mut arguments = Vec::new();
+        if self.peek() != TokenKind::Close {
+            arguments.push(self.sum()?);
+            while self.peek() == TokenKind::Comma {
+                self.next();
+                arguments.push(self.sum()?);
+            }
+        }
+        let close = self.next();
+        if close.kind != TokenKind::Close {
+            self.position -= 1;
+            return Err(self.unexpected("`,` or `)`"));
+        }
+
+        let span = span.start..close.span.end;
+        let expected = match function {
+            Function::Unary(_) => 1,
+            Function::Binary(_) => 2,
+        };
+        if arguments.len() != expected {
+            return Err(FormulaError {
+                span,
+                message: format!(
+                    "`{name}` expects {expected} argument{} but got {}",
+                    if expected == 1 { "" } else { "s" },
+                    arguments.len()
+                ),
+            });
+        }
+        Ok(match function {
+            Function::Unary(unary) => self.insert(Op::Unary(unary, arguments[0])),
+            Function::Binary(binary) => self.insert(Op::Binary(binary, (arguments[0], arguments[1]))),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{engine::Values, structure::Equivalence};
+
+    #[test]
+    fn parse_with_symbols() {
+        let mut ops = Operations::default();
+        let [a, x] = ops.vars();
+        let mut symbols = HashMap::from([("a".to_string(), a), ("x".to_string(), x)]);
+
+        let mut parser = FormulaParser::new(&mut symbols).create_vars(true);
+        let loss = parser.parse(&mut ops, "ln1p(exp(a*x)) - y").unwrap();
+        let y = symbols["y"];
+
+        let expected = {
+            let mut ops = Operations::default();
+            let [a, x, y] = ops.vars();
+            let loss = ops.insert((a * x).exp().ln_1p() - y);
+            (ops, loss)
+        };
+        assert!(ops.is_equivalent(&[loss], &expected.0, &[expected.1], Equivalence::STRUCTURAL));
+
+        let mut values = Values::new(ops.len());
+        values[a] = 0.5;
+        values[x] = 2.0;
+        values[y] = 0.25;
+        ops.forward(&mut values);
+        assert_eq!(values[loss], 1f64.exp().ln_1p() - 0.25);
+    }
+
+    #[test]
+    fn precedence_and_literals() {
+        let mut ops = Operations::default();
+        let x = ops.var();
+        let mut symbols = HashMap::from([("x".to_string(), x)]);
+        let mut parser = FormulaParser::new(&mut symbols);
+
+        let cases = [
+            ("-x^2 + 2*x - 1", -9.0 + 6.0 - 1.0),
+            ("x^-1 / 2", 1.0 / 6.0),
+            ("2^x^2", 2f64.powf(9.0)),
+            ("(x - 1) * -2.5e-1", -0.5),
+            ("pow(x, 2) - pow_2(x)", 0.0),
+            ("scale_grad(x, 0.5) + relu(-x) + detach(x)", 6.0),
+            (
+                "exp2(1) + expm1(0) + round_ste(0.75) + recip(4)",
+                2.0 + 0.0 + 1.0 + 0.25,
+            ),
+        ];
+        for (text, expected) in cases {
+            let node = parser.parse(&mut ops, text).unwrap();
+            let mut values = Values::new(ops.len());
+            values[x] = 3.0;
+            ops.forward(&mut values);
+            assert_eq!(values[node], expected, "{text}");
+        }
+        let square = parser.parse(&mut ops, "x^2").unwrap();
+        assert!(matches!(ops[square], Op::Unary(Unary::Pow2, _)));
+    }
+
+    #[test]
+    fn errors() {
+        let mut ops = Operations::default();
+        let x = ops.var();
+        let mut symbols = HashMap::from([("x".to_string(), x)]);
+        let mut parser = FormulaParser::new(&mut symbols);
+
+        let cases = [
+            ("x + y", 4..5, "unknown name `y`"),
+            ("sqrt(x)", 0..4, "unknown function `sqrt`"),
+            ("pow(x)", 0..6, "`pow` expects 2 arguments but got 1"),
+            ("x * (x + 1", 10..10, "expected `)` but found the end of the formula"),
+            ("x x", 2..3, "expected an operator but found `x`"),
+            ("x + * 2", 4..5, "expected a number, name or `(` but found `*`"),
+            ("x # 2", 2..3, "unexpected character `#`"),
+            ("1.2.3", 0..5, "invalid number `1.2.3`"),
+        ];
+        for (text, span, message) in cases {
+            let error = parser.parse(&mut ops, text).unwrap_err();
+            assert_eq!(
+                error,
+                FormulaError {
+                    span,
+                    message: message.to_string()
+                },
+                "{text}"
+            );
+        }
+        assert_eq!(ops.len(), 1, "failed parses are rolled back");
+    }
+
+    #[test]
+    fn roll_back_created_vars() {
+        let mut ops = Operations::default();
+        let mut symbols = HashMap::new();
+        let mut parser = FormulaParser::new(&mut symbols).create_vars(true);
+        assert!(parser.parse(&mut ops, "a * b +").is_err());
+        assert!(symbols.is_empty());
+        assert_eq!(ops.len(), 0);
+    }
+}
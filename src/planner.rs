@@ -0,0 +1,278 @@
+use crate::engine::{Error, NodeId, Nullary, Op, Operations, check_len, check_node};
+
+/// Assigns the nodes needed to compute a set of outputs to a small number of
+/// reusable registers, for inference with `Operations::forward_planned`.
+///
+/// A register is freed after the last use of the value it holds, so the number
+/// of registers is the maximum number of values that are alive at the same
+/// time rather than the number of nodes. The registers of leaves set by the
+/// user and of outputs are never reused, so the plan can be run repeatedly.
+/// Nodes that do not contribute to any of the outputs are not computed at all.
+#[derive(Debug, Clone)]
+pub struct MemoryPlan {
+    node_count: usize,
+    register_count: usize,
+    registers: Vec<Option<usize>>,
+    order: Vec<NodeId>,
+}
+
+impl MemoryPlan {
+    /// The number of nodes in the graph this plan was made for.
+    #[inline]
+    pub fn node_count(&self) -> usize {
+        self.node_count
+    }
+
+    /// The number of values the buffer for this plan holds.
+    #[inline]
+    pub fn register_count(&self) -> usize {
+        self.register_count
+    }
+
+    /// Returns the register assigned to `node`, or `None` if the node is not
+    /// needed to compute the outputs.
+    #[inline]
+    pub fn register(&self, node: NodeId) -> Option<usize> {
+        self.registers.get(usize::from(node)).copied().flatten()
+    }
+
+    /// Allocates a buffer for this plan with every element initialized to NaN.
+    #[inline]
+    pub fn values(&self) -> PlannedValues<'_> {
+        PlannedValues {
+            plan: self,
+            data: vec![f64::NAN; self.register_count],
+        }
+    }
+}
+
+/// A buffer of values laid out according to a `MemoryPlan`.
+///
+/// Indexing with a leaf before the forward pass sets its value, indexing with
+/// an output after the forward pass reads its value. Intermediate nodes share
+/// registers, so their values are overwritten during the forward pass.
+#[derive(Debug)]
+pub struct PlannedValues<'p> {
+    plan: &'p MemoryPlan,
+    data: Vec<f64>,
+}
+
+impl PlannedValues<'_> {
+    #[inline]
+    pub fn plan(&self) -> &MemoryPlan {
+        self.plan
+    }
+}
+
+impl std::ops::Index<NodeId> for PlannedValues<'_> {
+    type Output = f64;
+
+    #[inline]
+    #[track_caller]
+    fn index(&self, node: NodeId) -> &Self::Output {
+        match self.plan.register(node) {
+            Some(register) => &self.data[register],
+            None => panic!("node {} is not part of the memory plan", usize::from(node)),
+        }
+    }
+}
+
+impl std::ops::IndexMut<NodeId> for PlannedValues<'_> {
+    #[inline]
+    #[track_caller]
+    fn index_mut(&mut self, node: NodeId) -> &mut Self::Output {
+        match self.plan.register(node) {
+            Some(register) => &mut self.data[register],
+            None => panic!("node {} is not part of the memory plan", usize::from(node)),
+        }
+    }
+}
+
+/// Leaves whose values are set by the user must have a register before the
+/// forward pass starts.
+#[inline]
+fn is_user_leaf(op: Op) -> bool {
+    matches!(op, Op::Nullary(Nullary::Var | Nullary::Input | Nullary::Parameter))
+}
+
+impl Operations {
+    /// Plans the registers needed to compute `outputs`.
+    ///
+    /// Panics if an output is not a node in this graph.
+    #[track_caller]
+    pub fn plan_memory(&self, outputs: &[NodeId]) -> MemoryPlan {
+        match self.try_plan_memory(outputs) {
+            Ok(plan) => plan,
+            Err(error) => panic!("{}", error.display(self)),
+        }
+    }
+
+    pub fn try_plan_memory(&self, outputs: &[NodeId]) -> Result<MemoryPlan, Error> {
+        let mut is_output = vec![false; self.len()];
+        for &output in outputs {
+            check_node(output, self.len())?;
+            is_output[usize::from(output)] = true;
+        }
+
+        // Find the nodes that are needed and the last node that reads each.
+        let mut needed = is_output.clone();
+        let mut last_uses = vec![None; self.len()];
+        for node in self.nodes().rev() {
+            if !needed[usize::from(node)] {
+                continue;
+            }
+            for operand in self[node].operands() {
+                needed[usize::from(operand)] = true;
+                last_uses[usize::from(operand)].get_or_insert(usize::from(node));
+            }
+        }
+        let order: Vec<NodeId> = self.nodes().filter(|&node| needed[usize::from(node)]).collect();
+
+        let mut registers = vec![None; self.len()];
+        let mut register_count = 0;
+        let mut free = Vec::new();
+        let mut allocate = |free: &mut Vec<usize>| {
+            free.pop().unwrap_or_else(|| {
+                register_count += 1;
+                register_count - 1
+            })
+        };
+
+        for &node in &order {
+            if is_user_leaf(self[node]) {
+                registers[usize::from(node)] = Some(allocate(&mut free));
+            }
+        }
+
+        for &node in &order {
+            let op = self[node];
+            let index = usize::from(node);
+
+            // Operands are read before the result is written, so the result can
+            // reuse the register of an operand that dies here. User leaves keep
+            // their registers so their values survive the forward pass.
+            for (position, operand) in op.operands().enumerate() {
+                let operand_index = usize::from(operand);
+                let is_repeated = position == 1 && op.operands().next() == Some(operand);
+                let is_kept = is_output[operand_index] || is_user_leaf(self[operand]);
+                if last_uses[operand_index] == Some(index) && !is_kept && !is_repeated {
+                    free.push(registers[operand_index].expect("operands are assigned before use"));
+                }
+            }
+
+            if !is_user_leaf(op) {
+                registers[index] = Some(allocate(&mut free));
+            }
+        }
+
+        Ok(MemoryPlan {
+            node_count: self.len(),
+            register_count,
+            registers,
+            order,
+        })
+    }
+
+    /// Computes the outputs of `values.plan()` using its registers, see
+    /// `plan_memory`.
+    ///
+    /// Panics if the plan was made for a graph with a different number of
+    /// nodes.
+    #[track_caller]
+    pub fn forward_planned(&self, values: &mut PlannedValues) {
+        if let Err(error) = self.try_forward_planned(values) {
+            panic!("{}", error.display(self));
+        }
+    }
+
+    pub fn try_forward_planned(&self, values: &mut PlannedValues) -> Result<(), Error> {
+        let plan = values.plan;
+        check_len(self.len(), plan.node_count)?;
+
+        let register = |node: NodeId| plan.registers[usize::from(node)].expect("needed nodes have a register");
+        for &node in &plan.order {
+            let value = match self[node] {
+                Op::Nullary(Nullary::Var | Nullary::Input | Nullary::Parameter) => continue,
+                Op::Nullary(Nullary::Constant(constant)) => constant.value(),
+                Op::Unary(unary, a) => unary.forward(values.data[register(a)]),
+                Op::Binary(binary, (a, b)) => binary.forward(values.data[register(a)], values.data[register(b)]),
+            };
+            values.data[register(node)] = value;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::Values;
+
+    #[test]
+    fn long_chain() {
+        let mut ops = Operations::default();
+        let [x, y] = ops.vars();
+        let unused = ops.insert(x * y);
+        let mut z = x;
+        for _ in 0..100 {
+            z = ops.insert((z * y).tanh() + x);
+        }
+        let z2 = ops.insert(z + z);
+
+        let plan = ops.plan_memory(&[z, z2]);
+        assert_eq!(plan.register(unused), None);
+        assert!(plan.register_count() <= 5, "{}", plan.register_count());
+
+        let mut planned = plan.values();
+        planned[x] = 0.3;
+        planned[y] = -1.2;
+        ops.forward_planned(&mut planned);
+
+        let mut values = Values::new(ops.len());
+        values[x] = 0.3;
+        values[y] = -1.2;
+        ops.forward(&mut values);
+
+        assert_eq!(planned[z], values[z]);
+        assert_eq!(planned[z2], values[z2]);
+    }
+
+    #[test]
+    fn constants_and_repeated_operands() {
+        let mut ops = Operations::default();
+        let x = ops.var();
+        let c = ops.constant(3.0);
+        let a = ops.insert(x * c);
+        let b = ops.insert(a * a);
+        let d = ops.insert(b + c);
+
+        let plan = ops.plan_memory(&[d]);
+        let mut planned = plan.values();
+        planned[x] = 2.0;
+        ops.forward_planned(&mut planned);
+        assert_eq!(planned[d], 39.0);
+    }
+
+    #[test]
+    fn leaves_survive_repeated_passes() {
+        let mut ops = Operations::default();
+        let [x, w] = ops.vars();
+        let c = ops.constant(3.0);
+        let a = ops.insert(x * c);
+        let b = ops.insert(a * a);
+        let d = ops.insert(b + c);
+        let e = ops.insert(d * w);
+
+        let plan = ops.plan_memory(&[e]);
+        let mut planned = plan.values();
+        planned[x] = 2.0;
+        planned[w] = 0.5;
+        for _ in 0..2 {
+            ops.forward_planned(&mut planned);
+            assert_eq!(planned[x], 2.0);
+            assert_eq!(planned[w], 0.5);
+            assert_eq!(planned[e], 19.5);
+        }
+    }
+}
@@ -0,0 +1,483 @@
+//! Builders that reduce many nodes to one, like sums and dot products, and
+//! the softmax built on them.
+use std::borrow::Borrow;
+
+use crate::engine::{Binary, Constant, Error, NodeId, Nullary, Op, Operations, Unary, check_node};
+
+/// The shape of the tree of binary operations that combines the nodes.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub enum Reduction {
+    /// Combine the nodes one after the other, as in `((a + b) + c) + d`. The
+    /// depth grows linearly with the number of nodes.
+    #[default]
+    Chain,
+    /// Combine neighbouring pairs until one node is left, as in
+    /// `(a + b) + (c + d)`, so the depth grows logarithmically.
+    Tree,
+}
+
+/// Inserts reductions into a graph with a chosen `Reduction`, see
+/// `Operations::reduce_with`.
+///
+/// The methods accept anything that iterates over nodes, like a `Vec`, a slice,
+/// a `View<&[NodeId], _>` or an iterator.
+#[derive(Debug)]
+pub struct Reducer<'a> {
+    ops: &'a mut Operations,
+    reduction: Reduction,
+}
+
+fn collect<I>(nodes: I) -> Vec<NodeId>
+where
+    I: IntoIterator,
+    I::Item: Borrow<NodeId>,
+{
+    nodes.into_iter().map(|node| *node.borrow()).collect()
+}
+
+impl Reducer<'_> {
+    #[track_caller]
+    fn binary(&mut self, op: Binary, a: NodeId, b: NodeId) -> NodeId {
+        self.ops.insert(Op::Binary(op, (a, b)))
+    }
+
+    #[track_caller]
+    fn unary(&mut self, op: Unary, a: NodeId) -> NodeId {
+        self.ops.insert(Op::Unary(op, a))
+    }
+
+    #[track_caller]
+    fn constant(&mut self, value: f64) -> NodeId {
+        self.ops.insert(Op::Nullary(Nullary::Constant(Constant::new(value))))
+    }
+
+    /// Combines the nodes with `combine`, which must be associative, or returns
+    /// `None` if there are none.
+    #[track_caller]
+    fn fold(
+        &mut self,
+        mut nodes: Vec<NodeId>,
+        mut combine: impl FnMut(&mut Self, NodeId, NodeId) -> NodeId,
+    ) -> Option<NodeId> {
+        match self.reduction {
+            Reduction::Chain => {
+                let mut nodes = nodes.into_iter();
+                let first = nodes.next()?;
+                Some(nodes.fold(first, |acc, node| combine(self, acc, node)))
+            }
+            Reduction::Tree => {
+                while nodes.len() > 1 {
+                    nodes = nodes
+                        .chunks(2)
+                        .map(|pair| match *pair {
+                            [a, b] => combine(self, a, b),
+                            [a] => a,
+                            _ => unreachable!(),
+                        })
+                        .collect();
+                }
+                nodes.pop()
+            }
+        }
+    }
+
+    /// The largest of the nodes, which is only used to shift the arguments of
+    /// `exp` and is therefore detached. It is at least `f64::MIN` so that the
+    /// shift stays finite when every node is negative infinity.
+    #[track_caller]
+    fn detached_max(&mut self, nodes: &[NodeId]) -> Option<NodeId> {
+        let max = self.fold(nodes.to_vec(), |reducer, a, b| reducer.binary(Binary::Max, a, b))?;
+        let lowest = self.constant(f64::MIN);
+        let max = self.binary(Binary::Max, max, lowest);
+        Some(self.unary(Unary::Detach, max))
+    }
+
+    /// The sum of the nodes, or a constant zero if there are none.
+    ///
+    /// # Panics
+    ///
+    /// If a node is not in the graph.
+    #[track_caller]
+    pub fn sum<I>(&mut self, nodes: I) -> NodeId
+    where
+        I: IntoIterator,
+        I::Item: Borrow<NodeId>,
+    {
+        match self.fold(collect(nodes), |reducer, a, b| reducer.binary(Binary::Add, a, b)) {
+            Some(sum) => sum,
+            None => self.constant(0.0),
+        }
+    }
+
+    /// The product of the nodes, or a constant one if there are none.
+    ///
+    /// # Panics
+    ///
+    /// If a node is not in the graph.
+    #[track_caller]
+    pub fn product<I>(&mut self, nodes: I) -> NodeId
+    where
+        I: IntoIterator,
+        I::Item: Borrow<NodeId>,
+    {
+        match self.fold(collect(nodes), |reducer, a, b| reducer.binary(Binary::Mul, a, b)) {
+            Some(product) => product,
+            None => self.constant(1.0),
+        }
+    }
+
+    /// The arithmetic mean of the nodes.
+    ///
+    /// # Panics
+    ///
+    /// If there are no nodes or if a node is not in the graph.
+    #[track_caller]
+    pub fn mean<I>(&mut self, nodes: I) -> NodeId
+    where
+        I: IntoIterator,
+        I::Item: Borrow<NodeId>,
+    {
+        let nodes = collect(nodes);
+        assert!(!nodes.is_empty(), "cannot compute the mean of no nodes");
+        let count = nodes.len() as f64;
+        let sum = self.sum(nodes);
+        let count = self.constant(count);
+        self.binary(Binary::Div, sum, count)
+    }
+
+    /// The sum of the products of the corresponding nodes of `a` and `b`.
+    ///
+    /// # Panics
+    ///
+    /// If `a` and `b` have different lengths or if a node is not in the
+    /// graph, see `try_dot`.
+    #[track_caller]
+    pub fn dot<A, B>(&mut self, a: A, b: B) -> NodeId
+    where
+        A: IntoIterator,
+        A::Item: Borrow<NodeId>,
+        B: IntoIterator,
+        B::Item: Borrow<NodeId>,
+    {
+        match self.try_dot(a, b) {
+            Ok(dot) => dot,
+            Err(error) => panic!("{}", error.display(self.ops)),
+        }
+    }
+
+    /// Like `dot`, but returns `Error::OperandLengthMismatch` if `a` and `b`
+    /// have different lengths and `Error::NodeOutOfRange` if a node is not in
+    /// the graph. Nothing is inserted in that case.
+    pub fn try_dot<A, B>(&mut self, a: A, b: B) -> Result<NodeId, Error>
+    where
+        A: IntoIterator,
+        A::Item: Borrow<NodeId>,
+        B: IntoIterator,
+        B::Item: Borrow<NodeId>,
+    {
+        let (a, b) = (collect(a), collect(b));
+        if a.len() != b.len() {
+            return Err(Error::OperandLengthMismatch {
+                left: a.len(),
+                right: b.len(),
+            });
+        }
+        for &node in a.iter().chain(&b) {
+            check_node(node, self.ops.len())?;
+        }
+        let products: Vec<_> = a
+            .into_iter()
+            .zip(b)
+            .map(|(a, b)| self.binary(Binary::Mul, a, b))
+            .collect();
+        Ok(self.sum(products))
+    }
+
+    /// `ln(exp(x_0) + exp(x_1) + ...)`, computed as `m + ln(sum(exp(x_i - m)))`
+    /// where `m` is the largest node, so that `exp` cannot overflow. `m` is
+    /// detached because it does not change the result.
+    ///
+    /// # Panics
+    ///
+    /// If there are no nodes or if a node is not in the graph.
+    #[track_caller]
+    pub fn logsumexp<I>(&mut self, nodes: I) -> NodeId
+    where
+        I: IntoIterator,
+        I::Item: Borrow<NodeId>,
+    {
+        let nodes = collect(nodes);
+        let Some(max) = self.detached_max(&nodes) else {
+            panic!("cannot compute the logsumexp of no nodes");
+        };
+        let exps: Vec<_> = nodes
+            .into_iter()
+            .map(|node| {
+                let shifted = self.binary(Binary::Sub, node, max);
+                self.unary(Unary::Exp, shifted)
+            })
+            .collect();
+        let sum = self.sum(exps);
+        let ln = self.unary(Unary::Ln, sum);
+        self.binary(Binary::Add, max, ln)
+    }
+
+    /// `exp(x_i) / sum(exp(x_j))` for every node, shifted by the largest node
+    /// like `logsumexp`.
+    ///
+    /// # Panics
+    ///
+    /// If a node is not in the graph.
+    #[track_caller]
+    pub fn softmax<I>(&mut self, nodes: I) -> Vec<NodeId>
+    where
+        I: IntoIterator,
+        I::Item: Borrow<NodeId>,
+    {
+        let nodes = collect(nodes);
+        let Some(max) = self.detached_max(&nodes) else {
+            return Vec::new();
+        };
+        let exps: Vec<_> = nodes
+            .into_iter()
+            .map(|node| {
+                let shifted = self.binary(Binary::Sub, node, max);
+                self.unary(Unary::Exp, shifted)
+            })
+            .collect();
+        let sum = self.sum(&exps);
+        exps.into_iter().map(|exp| self.binary(Binary::Div, exp, sum)).collect()
+    }
+}
+
+impl Operations {
+    /// Returns a builder for reductions that combine the nodes as `reduction`
+    /// specifies, for example `ops.reduce_with(Reduction::Tree).sum(nodes)`.
+    #[inline]
+    pub fn reduce_with(&mut self, reduction: Reduction) -> Reducer<'_> {
+        Reducer { ops: self, reduction }
+    }
+
+    /// See `Reducer::sum`.
+    #[track_caller]
+    pub fn sum<I>(&mut self, nodes: I) -> NodeId
+    where
+        I: IntoIterator,
+        I::Item: Borrow<NodeId>,
+    {
+        self.reduce_with(Reduction::Chain).sum(nodes)
+    }
+
+    /// See `Reducer::product`.
+    #[track_caller]
+    pub fn product<I>(&mut self, nodes: I) -> NodeId
+    where
+        I: IntoIterator,
+        I::Item: Borrow<NodeId>,
+    {
+        self.reduce_with(Reduction::Chain).product(nodes)
+    }
+
+    /// See `Reducer::mean`.
+    #[track_caller]
+    pub fn mean<I>(&mut self, nodes: I) -> NodeId
+    where
+        I: IntoIterator,
+        I::Item: Borrow<NodeId>,
+    {
+        self.reduce_with(Reduction::Chain).mean(nodes)
+    }
+
+    /// See `Reducer::dot`.
+    #[track_caller]
+    pub fn dot<A, B>(&mut self, a: A, b: B) -> NodeId
+    where
+        A: IntoIterator,
+        A::Item: Borrow<NodeId>,
+        B: IntoIterator,
+        B::Item: Borrow<NodeId>,
+    {
+        self.reduce_with(Reduction::Chain).dot(a, b)
+    }
+
+    /// See `Reducer::try_dot`.
+    pub fn try_dot<A, B>(&mut self, a: A, b: B) -> Result<NodeId, Error>
+    where
+        A: IntoIterator,
+        A::Item: Borrow<NodeId>,
+        B: IntoIterator,
+        B::Item: Borrow<NodeId>,
+    {
+        self.reduce_with(Reduction::Chain).try_dot(a, b)
+    }
+
+    /// See `Reducer::logsumexp`.
+    #[track_caller]
+    pub fn logsumexp<I>(&mut self, nodes: I) -> NodeId
+    where
+        I: IntoIterator,
+        I::Item: Borrow<NodeId>,
+    {
+        self.reduce_with(Reduction::Chain).logsumexp(nodes)
+    }
+
+    /// See `Reducer::softmax`.
+    #[track_caller]
+    pub fn softmax<I>(&mut self, nodes: I) -> Vec<NodeId>
+    where
+        I: IntoIterator,
+        I::Item: Borrow<NodeId>,
+    {
+        self.reduce_with(Reduction::Chain).softmax(nodes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        engine::{Gradients, Values},
+        view::View,
+    };
+
+    fn evaluate(ops: &Operations, inputs: &[(NodeId, f64)]) -> Values {
+        let mut values = Values::new(ops.len());
+        for &(node, value) in inputs {
+            values[node] = value;
+        }
+        ops.forward(&mut values);
+        values
+    }
+
+    #[test]
+    fn sum_product_mean_dot() {
+        let mut ops = Operations::default();
+        let xs = ops.vars_vec(4);
+        let view = View::new(&xs[..], (2, 2));
+        let sum = ops.sum(view);
+        let product = ops.product(&xs);
+        let mean = ops.mean(xs.iter().copied());
+        let dot = ops.dot(&xs[..2], &xs[2..]);
+        let empty_sum = ops.sum(Vec::<NodeId>::new());
+        let empty_product = ops.product(Vec::<NodeId>::new());
+
+        let values = evaluate(&ops, &[(xs[0], 1.0), (xs[1], 2.0), (xs[2], 3.0), (xs[3], 4.0)]);
+        assert_eq!(values[sum], 10.0);
+        assert_eq!(values[product], 24.0);
+        assert_eq!(values[mean], 2.5);
+        assert_eq!(values[dot], 1.0 * 3.0 + 2.0 * 4.0);
+        assert_eq!(values[empty_sum], 0.0);
+        assert_eq!(values[empty_product], 1.0);
+    }
+
+    fn depth(ops: &Operations, node: NodeId) -> usize {
+        let mut depths = vec![0; ops.len()];
+        for node in ops.nodes() {
+            depths[usize::from(node)] = ops[node]
+                .operands()
+                .map(|operand| depths[usize::from(operand)] + 1)
+                .max()
+                .unwrap_or(0);
+        }
+        depths[usize::from(node)]
+    }
+
+    #[test]
+    fn tree_reduction() {
+        let mut ops = Operations::default();
+        let xs = ops.vars_vec(9);
+        let chain = ops.sum(&xs);
+        let tree = ops.reduce_with(Reduction::Tree).sum(&xs);
+        assert_eq!(depth(&ops, chain), 8);
+        assert_eq!(depth(&ops, tree), 4);
+
+        let inputs: Vec<_> = xs.iter().map(|&x| (x, usize::from(x) as f64)).collect();
+        let values = evaluate(&ops, &inputs);
+        assert_eq!(values[chain], 36.0);
+        assert_eq!(values[tree], 36.0);
+    }
+
+    #[test]
+    fn logsumexp_and_softmax_are_stable() {
+        let mut ops = Operations::default();
+        let xs = ops.vars_vec(3);
+        let lse = ops.reduce_with(Reduction::Tree).logsumexp(&xs);
+        let softmax = ops.softmax(&xs);
+        let args = [1000.0, 1001.0, 999.0];
+
+        let inputs: Vec<_> = xs.iter().copied().zip(args).collect();
+        let values = evaluate(&ops, &inputs);
+        let expected_lse = 1001.0 + ((-1f64).exp() + 1.0 + (-2f64).exp()).ln();
+        assert!((values[lse] - expected_lse).abs() < 1e-9);
+        for (&y, x) in softmax.iter().zip(args) {
+            assert!((values[y] - (x - expected_lse).exp()).abs() < 1e-12);
+        }
+
+        // The gradient of logsumexp is the softmax.
+        let mut gradients = Gradients::new(ops.len());
+        ops.backward(&values, &mut gradients, lse, 1.0);
+        for (&x, &y) in xs.iter().zip(&softmax) {
+            assert!((gradients[x] - values[y]).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn logsumexp_and_softmax_with_infinite_and_large_logits() {
+        let cases: [(&[f64], f64, &[f64]); 4] = [
+            (&[0.0, f64::NEG_INFINITY], 0.0, &[1.0, 0.0]),
+            (&[f64::NEG_INFINITY, 0.0, f64::NEG_INFINITY], 0.0, &[0.0, 1.0, 0.0]),
+            (&[1e300, 1e300], 1e300, &[0.5, 0.5]),
+            (&[-1e300, 1e300], 1e300, &[0.0, 1.0]),
+        ];
+        for (args, expected_lse, expected_softmax) in cases {
+            for reduction in [Reduction::Chain, Reduction::Tree] {
+                let mut ops = Operations::default();
+                let xs = ops.vars_vec(args.len());
+                let lse = ops.reduce_with(reduction).logsumexp(&xs);
+                let softmax = ops.reduce_with(reduction).softmax(&xs);
+
+                let inputs: Vec<_> = xs.iter().copied().zip(args.iter().copied()).collect();
+                let values = evaluate(&ops, &inputs);
+                assert_eq!(values[lse], expected_lse, "{args:?}");
+                let actual: Vec<_> = softmax.iter().map(|&y| values[y]).collect();
+                assert_eq!(actual, expected_softmax, "{args:?}");
+
+                let mut gradients = Gradients::new(ops.len());
+                ops.backward(&values, &mut gradients, lse, 1.0);
+                let actual: Vec<_> = xs.iter().map(|&x| gradients[x]).collect();
+                assert_eq!(actual, expected_softmax, "{args:?}");
+            }
+        }
+
+        let mut ops = Operations::default();
+        let xs = ops.vars_vec(2);
+        let lse = ops.logsumexp(&xs);
+        let values = evaluate(&ops, &[(xs[0], f64::NEG_INFINITY), (xs[1], f64::NEG_INFINITY)]);
+        assert_eq!(values[lse], f64::NEG_INFINITY);
+    }
+
+    #[test]
+    #[should_panic(expected = "operand length mismatch: 3 and 2 nodes")]
+    fn dot_length_mismatch() {
+        let mut ops = Operations::default();
+        let xs = ops.vars_vec(3);
+        ops.dot(&xs, &xs[1..]);
+    }
+
+    #[test]
+    fn try_dot_errors() {
+        let mut ops = Operations::default();
+        let xs = ops.vars_vec(3);
+        assert_eq!(
+            ops.try_dot(&xs, &xs[1..]),
+            Err(Error::OperandLengthMismatch { left: 3, right: 2 })
+        );
+        let foreign = NodeId::from(3);
+        assert_eq!(
+            ops.try_dot([xs[0]], [foreign]),
+            Err(Error::NodeOutOfRange { node: foreign, len: 3 })
+        );
+        assert_eq!(ops.len(), 3);
+        assert!(ops.try_dot(&xs, &xs).is_ok());
+    }
+}
@@ -0,0 +1,306 @@
+use crate::{
+    deref_slice::DerefSlice,
+    engine::{Error, NodeId, Op, Operations, check_node},
+    view::{IndexTuple, View},
+};
+
+/// Maps nodes of a source graph to the nodes they were copied to in a
+/// destination graph.
+#[derive(Debug, Clone)]
+pub struct NodeRemap(Vec<Option<NodeId>>);
+
+impl NodeRemap {
+    /// Creates a remap for a source graph with `len` nodes where no node has
+    /// been mapped yet.
+    #[inline]
+    pub fn new(len: usize) -> Self {
+        Self(vec![None; len])
+    }
+
+    /// Returns the number of nodes in the source graph.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Returns the node `node` was mapped to, if any.
+    #[inline]
+    pub fn get(&self, node: NodeId) -> Option<NodeId> {
+        self.0.get(usize::from(node)).copied().flatten()
+    }
+
+    #[inline]
+    pub fn set(&mut self, node: NodeId, target: NodeId) {
+        self.0[usize::from(node)] = Some(target);
+    }
+
+    /// Translates a view of source nodes into an owned view of destination
+    /// nodes with the same shape.
+    ///
+    /// Panics if any of the nodes was not mapped.
+    #[track_caller]
+    pub fn view<A, X>(&self, view: View<A, X>) -> View<Vec<NodeId>, X>
+    where
+        A: DerefSlice<Item = NodeId>,
+        X: IndexTuple,
+    {
+        View::new(view.iter().map(|&node| self[node]).collect(), view.shape())
+    }
+}
+
+impl std::ops::Index<NodeId> for NodeRemap {
+    type Output = NodeId;
+
+    #[inline]
+    #[track_caller]
+    fn index(&self, node: NodeId) -> &Self::Output {
+        self.0[usize::from(node)]
+            .as_ref()
+            .unwrap_or_else(|| panic!("node {} was not mapped", usize::from(node)))
+    }
+}
+
+impl Operations {
+    /// Copies every node of `other` into this graph.
+    pub fn append(&mut self, other: &Operations) -> NodeRemap {
+        self.append_with(other, [])
+    }
+
+    /// Copies every node of `other` into this graph, except for the nodes in
+    /// `bindings` which are replaced by existing nodes of this graph. This is
+    /// typically used to connect the variables of `other` to the outputs of
+    /// this graph.
+    ///
+    /// If this graph records metadata, the copies keep the names and groups of
+    /// `other` below the current scope.
+    ///
+    /// Panics if a binding refers to a node that does not exist or if a node
+    /// of `other` reads an operand that is not computed before it.
+    #[track_caller]
+    pub fn append_with(
+        &mut self,
+        other: &Operations,
+        bindings: impl IntoIterator<Item = (NodeId, NodeId)>,
+    ) -> NodeRemap {
+        match self.try_append_with(other, bindings) {
+            Ok(remap) => remap,
+            // The nodes of the error may belong to either graph, so they are
+            // not looked up by name.
+            Err(error) => panic!("{error}"),
+        }
+    }
+
+    /// Like `append_with`, but returns an error instead of panicking. Nothing
+    /// is inserted in that case.
+    pub fn try_append_with(
+        &mut self,
+        other: &Operations,
+        bindings: impl IntoIterator<Item = (NodeId, NodeId)>,
+    ) -> Result<NodeRemap, Error> {
+        let start = self.len();
+        let mut remap = NodeRemap::new(other.len());
+        for (source, target) in bindings {
+            check_node(source, other.len())?;
+            check_node(target, self.len())?;
+            remap.set(source, target);
+        }
+        for node in other.nodes() {
+            if remap.get(node).is_none() {
+                let inserted = try_remap_op(other[node], node, &remap).and_then(|op| self.try_insert(op));
+                let target = match inserted {
+                    Ok(target) => target,
+                    Err(error) => {
+                        self.truncate(start);
+                        return Err(error);
+                    }
+                };
+                self.copy_metadata(target, other, node);
+                remap.set(node, target);
+            }
+        }
+        self.debug_assert_valid_from(start);
+        Ok(remap)
+    }
+
+    /// Copies `roots` and all of their ancestors into a new graph. Nodes that
+    /// do not contribute to any of the roots are left out. If this graph
+    /// records metadata, so does the new graph and the copies keep their names
+    /// and groups.
+    ///
+    /// Panics if a root is not a node in this graph or if a node reads an
+    /// operand that is not computed before it.
+    #[track_caller]
+    pub fn extract(&self, roots: &[NodeId]) -> (Operations, NodeRemap) {
+        let mut in_cone = vec![false; self.len()];
+        for &root in roots {
+            if let Err(error) = check_node(root, self.len()) {
+                panic!("{}", error.display(self));
+            }
+            in_cone[usize::from(root)] = true;
+        }
+        for node in self.nodes().rev() {
+            if !in_cone[usize::from(node)] {
+                continue;
+            }
+            match self[node] {
+                Op::Nullary(_) => {}
+                Op::Unary(_, a) => in_cone[usize::from(a)] = true,
+                Op::Binary(_, (a, b)) => {
+                    in_cone[usize::from(a)] = true;
+                    in_cone[usize::from(b)] = true;
+                }
+            }
+        }
+
+        let mut ops = Operations::default();
+        ops.record_metadata(self.is_recording_metadata());
+        let mut remap = NodeRemap::new(self.len());
+        for node in self.nodes().filter(|&node| in_cone[usize::from(node)]) {
+            let op = match try_remap_op(self[node], node, &remap) {
+                Ok(op) => op,
+                Err(error) => panic!("{}", error.display(self)),
+            };
+            let target = ops.insert(op);
+            ops.copy_metadata(target, self, node);
+            remap.set(node, target);
+        }
+        ops.debug_assert_valid_from(0);
+        (ops, remap)
+    }
+}
+
+/// Translates the operands of `op`, the operation of `node` in a graph of
+/// `remap.len()` nodes. Fails if an operand does not exist or was not mapped
+/// yet, which means that it is not computed before `node`.
+#[inline]
+fn try_remap_op(op: Op, node: NodeId, remap: &NodeRemap) -> Result<Op, Error> {
+    let operand = |operand: NodeId| {
+        check_node(operand, remap.len())?;
+        remap.get(operand).ok_or(Error::ForwardReference { node, operand })
+    };
+    Ok(match op {
+        Op::Nullary(nullary) => Op::Nullary(nullary),
+        Op::Unary(unary, a) => Op::Unary(unary, operand(a)?),
+        Op::Binary(binary, (a, b)) => Op::Binary(binary, (operand(a)?, operand(b)?)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        engine::{Binary, Values},
+        nn::{self, FullyConnectedLayer},
+    };
+
+    #[test]
+    fn append_with_bindings() {
+        let mut encoder = Operations::default();
+        let x = encoder.var();
+        let z = encoder.insert(x.pow_2());
+
+        let mut decoder = Operations::default();
+        let z_in = decoder.var();
+        let y = decoder.insert(z_in + z_in);
+
+        let remap = encoder.append_with(&decoder, [(z_in, z)]);
+        assert_eq!(remap[z_in], z);
+        assert_eq!(encoder.len(), 3);
+
+        let mut values = Values::new(encoder.len());
+        values[x] = 3.0;
+        encoder.forward(&mut values);
+        assert_eq!(values[remap[y]], 18.0);
+    }
+
+    #[test]
+    fn append_forward_reference() {
+        let mut other = Operations::default();
+        let [x, y] = other.vars();
+        let a = other.insert(x + y);
+        let b = other.insert(a * a);
+        other[a] = Op::Binary(Binary::Add, (x, b));
+
+        let mut ops = Operations::default();
+        let z = ops.var();
+        assert_eq!(
+            ops.try_append_with(&other, [(x, z)]).map(|remap| remap.len()),
+            Err(Error::ForwardReference { node: a, operand: b })
+        );
+        assert_eq!(ops.len(), 1);
+    }
+
+    #[test]
+    fn append_and_extract_keep_metadata() {
+        let mut other = Operations::default();
+        other.record_metadata(true);
+        let [x, y] = other.vars();
+        other.set_name(x, "x");
+        other.set_group(x, "inputs");
+        let a = other.insert(x * y);
+        other.set_name(a, "a");
+
+        let mut ops = Operations::default();
+        ops.record_metadata(true);
+        ops.push_scope("model");
+        let remap = ops.append(&other);
+        ops.pop_scope();
+        assert_eq!(ops.name(remap[x]), Some("model.x"));
+        assert_eq!(ops.group(remap[x]), Some("model.inputs"));
+        assert_eq!(ops.name(remap[y]), None);
+        assert_eq!(ops.name(remap[a]), Some("model.a"));
+
+        let (cone, remap) = other.extract(&[a]);
+        assert!(cone.is_recording_metadata());
+        assert_eq!(cone.name(remap[a]), Some("a"));
+        assert_eq!(cone.group(remap[x]), Some("inputs"));
+
+        let mut plain = Operations::default();
+        let remap = plain.append(&other);
+        assert_eq!(plain.name(remap[a]), None);
+    }
+
+    #[test]
+    fn extract_cone() {
+        let mut ops = Operations::default();
+        let [a, b, c] = ops.vars();
+        let ab = ops.insert(a * b);
+        let _bc = ops.insert(b * c);
+
+        let (cone, remap) = ops.extract(&[ab]);
+        assert_eq!(cone.len(), 3);
+        assert_eq!(remap.get(c), None);
+
+        let mut values = Values::new(cone.len());
+        values[remap[a]] = 2.0;
+        values[remap[b]] = 5.0;
+        cone.forward(&mut values);
+        assert_eq!(values[remap[ab]], 10.0);
+    }
+
+    #[test]
+    fn append_layer_view() {
+        let mut model = Operations::default();
+        let input = nn::input_layer_vec((nn::B(2), nn::O(3)), &mut model);
+        let layer = FullyConnectedLayer::new(
+            input.as_deref().reindex(nn::batched_output_to_input),
+            nn::O(2),
+            &mut model,
+            |x| x,
+        );
+
+        let mut ops = Operations::default();
+        let _offset = ops.vars::<5>();
+        let remap = ops.append(&model);
+        let outputs = remap.view(layer.outputs());
+        assert_eq!(outputs.shape(), layer.outputs().shape());
+        for (&a, &b) in outputs.iter().zip(layer.outputs().iter()) {
+            assert_eq!(usize::from(a), usize::from(b) + 5);
+        }
+    }
+}
@@ -0,0 +1,219 @@
+use crate::engine::{Error, NodeId, Op, Operations, check_node};
+
+/// The length of an `Operations` at some point, see `Operations::mark`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Mark(usize);
+
+impl Mark {
+    /// The number of nodes in the graph when the mark was made.
+    #[inline]
+    pub fn len(self) -> usize {
+        self.0
+    }
+
+    #[inline]
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+}
+
+#[inline]
+fn check_operands(op: Op, node: NodeId) -> Result<(), Error> {
+    match op.operands().find(|&operand| usize::from(operand) >= usize::from(node)) {
+        Some(operand) => Err(Error::ForwardReference { node, operand }),
+        None => Ok(()),
+    }
+}
+
+impl Operations {
+    /// Replaces the operation of `node` and returns the previous one. The node
+    /// keeps its metadata and every node that read it now reads the result of
+    /// `op`.
+    ///
+    /// Panics if `node` is not a node in this graph or if an operand of `op`
+    /// is not computed before `node`.
+    #[track_caller]
+    pub fn replace(&mut self, node: NodeId, op: Op) -> Op {
+        match self.try_replace(node, op) {
+            Ok(op) => op,
+            Err(error) => panic!("{}", error.display(self)),
+        }
+    }
+
+    pub fn try_replace(&mut self, node: NodeId, op: Op) -> Result<Op, Error> {
+        check_node(node, self.len())?;
+        check_operands(op, node)?;
+        let previous = std::mem::replace(&mut self[node], op);
+        self.debug_assert_valid_from(usize::from(node));
+        Ok(previous)
+    }
+
+    /// Makes every node that reads `old` read `new` instead and returns the
+    /// number of operands that were changed. `old` itself is left in the graph
+    /// and may become unused.
+    ///
+    /// Panics if `old` or `new` is not a node in this graph or if `new` is not
+    /// computed before one of the nodes that read `old`. Nothing is changed in
+    /// that case.
+    #[track_caller]
+    pub fn rewire(&mut self, old: NodeId, new: NodeId) -> usize {
+        match self.try_rewire(old, new) {
+            Ok(count) => count,
+            Err(error) => panic!("{}", error.display(self)),
+        }
+    }
+
+    pub fn try_rewire(&mut self, old: NodeId, new: NodeId) -> Result<usize, Error> {
+        check_node(old, self.len())?;
+        check_node(new, self.len())?;
+
+        // Only nodes after `old` can read it.
+        let start = usize::from(old) + 1;
+        let readers = (start..self.len())
+            .map(NodeId::from)
+            .filter(|&node| self[node].operands().any(|operand| operand == old));
+        for node in readers {
+            if usize::from(new) >= usize::from(node) {
+                return Err(Error::ForwardReference { node, operand: new });
+            }
+        }
+
+        let mut count = 0;
+        for node in (start..self.len()).map(NodeId::from) {
+            let mut rewire = |operand: &mut NodeId| {
+                if *operand == old {
+                    *operand = new;
+                    count += 1;
+                }
+            };
+            match &mut self[node] {
+                Op::Nullary(_) => {}
+                Op::Unary(_, a) => rewire(a),
+                Op::Binary(_, (a, b)) => {
+                    rewire(a);
+                    rewire(b);
+                }
+            }
+        }
+        self.debug_assert_valid_from(start);
+        Ok(count)
+    }
+
+    /// Remembers the current length so the nodes inserted after this call can
+    /// be removed again with `truncate_to`.
+    #[inline]
+    pub fn mark(&self) -> Mark {
+        Mark(self.len())
+    }
+
+    /// Removes every node inserted after `mark` was made, along with its
+    /// metadata. Does nothing if the graph has fewer nodes than `mark`.
+    ///
+    /// Node ids and buffers like `Values` created after the mark are no longer
+    /// valid for this graph afterwards.
+    #[inline]
+    pub fn truncate_to(&mut self, mark: Mark) {
+        self.truncate(mark.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{Constant, Gradients, Input, Nullary, Parameter, Unary, Values};
+
+    #[test]
+    fn replace_activation() {
+        let mut ops = Operations::default();
+        let (x, w) = ops.insert((Input, Parameter));
+        let h = ops.insert((w * x).relu());
+        let y = ops.insert(h + x);
+
+        let Op::Unary(Unary::ReLU, a) = ops[h] else {
+            panic!("expected relu");
+        };
+        assert_eq!(ops.replace(h, Op::Unary(Unary::TanH, a)), Op::Unary(Unary::ReLU, a));
+
+        let mut values = Values::new(ops.len());
+        values[x] = -1.0;
+        values[w] = 2.0;
+        ops.forward(&mut values);
+        assert_eq!(values[y], (-2.0f64).tanh() - 1.0);
+    }
+
+    #[test]
+    fn replace_with_constant() {
+        let mut ops = Operations::default();
+        let x = ops.var();
+        let h = ops.insert(x.exp().ln_1p());
+        let y = ops.insert(h * x);
+
+        ops.replace(h, Op::Nullary(Nullary::Constant(Constant::new(3.0))));
+
+        let mut values = Values::new(ops.len());
+        values[x] = 2.0;
+        ops.forward(&mut values);
+        assert_eq!(values[y], 6.0);
+        let mut gradients = Gradients::new(ops.len());
+        ops.backward(&values, &mut gradients, y, 1.0);
+        assert_eq!(gradients[x], 3.0);
+    }
+
+    #[test]
+    fn replace_rejects_forward_reference() {
+        let mut ops = Operations::default();
+        let x = ops.var();
+        let y = ops.insert(x.exp());
+        let z = ops.insert(y.ln());
+
+        let op = ops[y];
+        assert_eq!(
+            ops.try_replace(y, Op::Unary(Unary::Exp, z)),
+            Err(Error::ForwardReference { node: y, operand: z })
+        );
+        assert_eq!(ops[y], op);
+    }
+
+    #[test]
+    fn rewire() {
+        let mut ops = Operations::default();
+        let [x, y] = ops.vars();
+        let a = ops.insert(x.exp());
+        let b = ops.insert(a * a);
+        ops.insert(b + y);
+        let c = ops.insert(y.tanh());
+
+        assert_eq!(ops.rewire(a, y), 2);
+        assert_eq!(ops[b].operands().collect::<Vec<_>>(), [y, y]);
+
+        // `c` is computed after `b`, so it cannot replace `y` in `b`.
+        let op = ops[b];
+        assert_eq!(
+            ops.try_rewire(y, c),
+            Err(Error::ForwardReference { node: b, operand: c })
+        );
+        assert_eq!(ops[b], op);
+    }
+
+    #[test]
+    fn truncate_to_mark() {
+        let mut ops = Operations::default();
+        ops.record_metadata(true);
+        let (x, w) = ops.insert((Input, Parameter));
+        let y = ops.insert(w * x);
+        let mark = ops.mark();
+
+        for step in 0..3 {
+            let target = ops.constant(step as f64);
+            let loss = ops.insert((y - target).pow_2());
+            ops.set_name(loss, "loss");
+            assert_eq!(ops.len(), mark.len() + 3);
+            ops.truncate_to(mark);
+        }
+
+        assert_eq!(ops.len(), 3);
+        let z = ops.insert(y.exp());
+        assert_eq!(usize::from(z), mark.len());
+        assert_eq!(ops.name(z), None);
+    }
+}
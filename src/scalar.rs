@@ -0,0 +1,292 @@
+//! Write numeric code once and run it either on `f64` or to build a graph.
+//!
+//! ```
+//! use std::cell::RefCell;
+//!
+//! use micrograd_rs::{engine::Operations, scalar::{Scalar, Traced}};
+//!
+//! fn neuron<S: Scalar>(x: S, w: S, b: S) -> S {
+//!     (w * x + b).tanh()
+//! }
+//!
+//! let y = neuron(0.5, -1.0, 0.25);
+//!
+//! let ops = RefCell::new(Operations::default());
+//! let [x, w, b] = [(); 3].map(|_| Traced::var(&ops));
+//! let node = neuron(x, w, b).node();
+//! # let _ = (y, node);
+//! ```
+use std::{
+    cell::RefCell,
+    fmt,
+    ops::{Add, Div, Mul, Neg, Sub},
+};
+
+use crate::{
+    engine::{Binary, Constant, NodeId, Nullary, Op, Operations, Unary, Var, check_node},
+    syntax::{call_with_binary_variants, call_with_unary_variants},
+};
+
+/// Negation is provided by `std::ops::Neg`.
+macro_rules! unary_method {
+    (Neg, neg) => {};
+    ($V:ident, $v:ident) => {
+        #[inline]
+        #[track_caller]
+        fn $v(self) -> Self {
+            self.unary(Unary::$V)
+        }
+    };
+}
+
+/// The arithmetic operators are provided by `std::ops`.
+macro_rules! binary_method {
+    (Add, add) => {};
+    (Sub, sub) => {};
+    (Mul, mul) => {};
+    (Div, div) => {};
+    ($V:ident, $v:ident) => {
+        #[inline]
+        #[track_caller]
+        fn $v(self, rhs: Self) -> Self {
+            self.binary(Binary::$V, rhs)
+        }
+    };
+}
+
+/// A number that supports the arithmetic and math of `Unary` and `Binary`.
+///
+/// For `f64` the operations are computed right away with the forward rules of
+/// the engine. For `Traced` they are inserted into a graph instead. Besides
+/// the operators and `unary` and `binary`, there is a method for every
+/// operation named like the corresponding `Expr` method.
+pub trait Scalar:
+    Copy
+    + Add<Output = Self>
+    + Sub<Output = Self>
+    + Mul<Output = Self>
+    + Div<Output = Self>
+    + Neg<Output = Self>
+    + Add<f64, Output = Self>
+    + Sub<f64, Output = Self>
+    + Mul<f64, Output = Self>
+    + Div<f64, Output = Self>
+{
+    /// Returns `value` in the same context as `self`, which is a new constant
+    /// node for `Traced`.
+    fn constant(self, value: f64) -> Self;
+
+    fn unary(self, op: Unary) -> Self;
+
+    fn binary(self, op: Binary, rhs: Self) -> Self;
+
+    call_with_unary_variants!(unary_method);
+    call_with_binary_variants!(binary_method);
+}
+
+impl Scalar for f64 {
+    #[inline]
+    fn constant(self, value: f64) -> Self {
+        value
+    }
+
+    #[inline]
+    fn unary(self, op: Unary) -> Self {
+        op.forward(self)
+    }
+
+    #[inline]
+    fn binary(self, op: Binary, rhs: Self) -> Self {
+        op.forward(self, rhs)
+    }
+}
+
+/// A node in a graph that inserts a new node for every operation applied to
+/// it, to record the operations of ordinary numeric code.
+///
+/// The graph is shared through a `RefCell` because the operators cannot take
+/// it as an argument. Operations panic if the graph is already borrowed or if
+/// the operands belong to different graphs.
+#[derive(Copy, Clone)]
+pub struct Traced<'a> {
+    ops: &'a RefCell<Operations>,
+    node: NodeId,
+}
+
+impl<'a> Traced<'a> {
+    /// Panics if `node` is not a node in `ops`.
+    #[track_caller]
+    pub fn new(ops: &'a RefCell<Operations>, node: NodeId) -> Self {
+        if let Err(error) = check_node(node, ops.borrow().len()) {
+            panic!("{error}");
+        }
+        Self { ops, node }
+    }
+
+    /// Inserts a new variable into `ops`.
+    #[track_caller]
+    pub fn var(ops: &'a RefCell<Operations>) -> Self {
+        let node = ops.borrow_mut().insert(Var);
+        Self { ops, node }
+    }
+
+    #[inline]
+    pub fn node(self) -> NodeId {
+        self.node
+    }
+
+    #[inline]
+    pub fn ops(self) -> &'a RefCell<Operations> {
+        self.ops
+    }
+
+    #[track_caller]
+    fn insert(self, op: Op) -> Self {
+        let node = self.ops.borrow_mut().insert(op);
+        Self { ops: self.ops, node }
+    }
+
+    #[track_caller]
+    fn operand(self, other: Self) -> NodeId {
+        assert!(
+            std::ptr::eq(self.ops, other.ops),
+            "operands of a traced operation belong to different graphs"
+        );
+        other.node
+    }
+}
+
+impl fmt::Debug for Traced<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Traced").field(&self.node).finish()
+    }
+}
+
+impl Scalar for Traced<'_> {
+    #[inline]
+    #[track_caller]
+    fn constant(self, value: f64) -> Self {
+        self.insert(Op::Nullary(Nullary::Constant(Constant::new(value))))
+    }
+
+    #[inline]
+    #[track_caller]
+    fn unary(self, op: Unary) -> Self {
+        self.insert(Op::Unary(op, self.node))
+    }
+
+    #[inline]
+    #[track_caller]
+    fn binary(self, op: Binary, rhs: Self) -> Self {
+        self.insert(Op::Binary(op, (self.node, self.operand(rhs))))
+    }
+}
+
+impl Neg for Traced<'_> {
+    type Output = Self;
+
+    #[inline]
+    #[track_caller]
+    fn neg(self) -> Self::Output {
+        self.unary(Unary::Neg)
+    }
+}
+
+macro_rules! impl_traced_op {
+    ($V:ident, $v:ident) => {
+        impl $V for Traced<'_> {
+            type Output = Self;
+
+            #[inline]
+            #[track_caller]
+            fn $v(self, rhs: Self) -> Self::Output {
+                self.binary(Binary::$V, rhs)
+            }
+        }
+
+        impl $V<f64> for Traced<'_> {
+            type Output = Self;
+
+            #[inline]
+            #[track_caller]
+            fn $v(self, rhs: f64) -> Self::Output {
+                self.binary(Binary::$V, self.constant(rhs))
+            }
+        }
+    };
+}
+impl_traced_op!(Add, add);
+impl_traced_op!(Sub, sub);
+impl_traced_op!(Mul, mul);
+impl_traced_op!(Div, div);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{Gradients, Values};
+
+    fn loss<S: Scalar>(x: S, w: S, b: S, y: S) -> S {
+        let y_pred = (w * x + b).tanh();
+        (y_pred - y).pow_2() / 2.0 + w.pow_2().ln_1p() * 0.1
+    }
+
+    #[test]
+    fn same_result_for_f64_and_graph() {
+        let args = [0.5, -1.5, 0.25, 1.0];
+        let expected = loss(args[0], args[1], args[2], args[3]);
+
+        let ops = RefCell::new(Operations::default());
+        let [x, w, b, y] = [(); 4].map(|_| Traced::var(&ops));
+        let output = loss(x, w, b, y).node();
+        let leaves = [x, w, b, y].map(Traced::node);
+
+        let ops = ops.into_inner();
+        let mut values = Values::new(ops.len());
+        for (leaf, arg) in leaves.into_iter().zip(args) {
+            values[leaf] = arg;
+        }
+        ops.forward(&mut values);
+        assert_eq!(values[output], expected);
+
+        let mut gradients = Gradients::new(ops.len());
+        ops.backward(&values, &mut gradients, output, 1.0);
+        let y_pred = (args[1] * args[0] + args[2]).tanh();
+        assert_eq!(
+            gradients[leaves[0]],
+            (y_pred - args[3]) * (1.0 - y_pred * y_pred) * args[1]
+        );
+    }
+
+    #[test]
+    fn every_operation() {
+        let (a, b) = (0.75, 1.25);
+        let ops = RefCell::new(Operations::default());
+        let [x, y] = [(); 2].map(|_| Traced::var(&ops));
+        let unary = Unary::ALL.map(|op| (x.unary(op).node(), a.unary(op)));
+        let binary = Binary::ALL.map(|op| (x.binary(op, y).node(), a.binary(op, b)));
+        let methods = [
+            (x.exp_m1().node(), a.exp_m1()),
+            (x.pow(y).node(), a.pow(b)),
+            ((-x).node(), -a),
+        ];
+
+        let (x, y) = (x.node(), y.node());
+
+        let ops = ops.into_inner();
+        let mut values = Values::new(ops.len());
+        values[x] = a;
+        values[y] = b;
+        ops.forward(&mut values);
+        for (node, expected) in unary.into_iter().chain(binary).chain(methods) {
+            assert_eq!(values[node], expected, "{}", ops[node].name());
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "different graphs")]
+    fn different_graphs() {
+        let ops1 = RefCell::new(Operations::default());
+        let ops2 = RefCell::new(Operations::default());
+        let _ = Traced::var(&ops1) + Traced::var(&ops2);
+    }
+}
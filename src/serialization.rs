@@ -0,0 +1,336 @@
+//! A versioned binary format for `Operations`, so that a graph can be loaded
+//! and evaluated without the code that built it.
+//!
+//! All integers and floats are little endian. The layout is:
+//!
+//! - the magic bytes `MGRS` and a `u32` format version,
+//! - a `u8` with flags for the optional sections,
+//! - a `u64` node count followed by every node: a `u8` kind (0 nullary, 1
+//!   unary, 2 binary), a `u8` variant and then the payload, which is the
+//!   constant value for constants and the `u64` operands for the other
+//!   operations,
+//! - if present, the name and group of every node as length prefixed UTF-8
+//!   with `u64::MAX` meaning absent,
+//! - if present, one `f64` value per node.
+//!
+//! Unary and binary variants are encoded by their position in `Unary::ALL`
+//! and `Binary::ALL`, so new variants must be appended there.
+pub use std::io::Result;
+use std::io::{Error, ErrorKind, Read, Write};
+
+use byteorder::{LE, ReadBytesExt, WriteBytesExt};
+
+use crate::engine::{Binary, Constant, NodeId, Nullary, Op, Operations, Unary, Values};
+
+const MAGIC: [u8; 4] = *b"MGRS";
+const VERSION: u32 = 1;
+
+const FLAG_METADATA: u8 = 1 << 0;
+const FLAG_VALUES: u8 = 1 << 1;
+
+const KIND_NULLARY: u8 = 0;
+const KIND_UNARY: u8 = 1;
+const KIND_BINARY: u8 = 2;
+
+const NULLARY_VAR: u8 = 0;
+const NULLARY_INPUT: u8 = 1;
+const NULLARY_PARAMETER: u8 = 2;
+const NULLARY_CONSTANT: u8 = 3;
+
+const ABSENT: u64 = u64::MAX;
+
+fn invalid_data(message: impl Into<String>) -> Error {
+    Error::new(ErrorKind::InvalidData, message.into())
+}
+
+/// Writes `ops` to `writer`. Node names and groups are included when `ops` is
+/// recording metadata and `values` are included when given.
+pub fn write_operations(ops: &Operations, values: Option<&Values>, writer: &mut impl Write) -> Result<()> {
+    if let Some(values) = values
+        && values.len() != ops.len()
+    {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            crate::engine::Error::LengthMismatch {
+                expected: ops.len(),
+                actual: values.len(),
+            },
+        ));
+    }
+
+    writer.write_all(&MAGIC)?;
+    writer.write_u32::<LE>(VERSION)?;
+    let mut flags = 0;
+    if ops.is_recording_metadata() {
+        flags |= FLAG_METADATA;
+    }
+    if values.is_some() {
+        flags |= FLAG_VALUES;
+    }
+    writer.write_u8(flags)?;
+
+    writer.write_u64::<LE>(ops.len() as u64)?;
+    for &op in ops {
+        write_op(op, writer)?;
+    }
+
+    if flags & FLAG_METADATA != 0 {
+        for node in ops.nodes() {
+            write_optional_str(ops.name(node), writer)?;
+            write_optional_str(ops.group(node), writer)?;
+        }
+    }
+
+    if let Some(values) = values {
+        for &value in values {
+            writer.write_f64::<LE>(value)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads a graph written by `write_operations`, along with its values if they
+/// were written.
+///
+/// Every operand is checked to refer to an earlier node, so the returned graph
+/// is in topological order.
+pub fn read_operations(reader: &mut impl Read) -> Result<(Operations, Option<Values>)> {
+    let mut magic = [0; 4];
+    reader.read_exact(&mut magic)?;
+    if magic != MAGIC {
+        return Err(invalid_data("not a serialized graph"));
+    }
+    let version = reader.read_u32::<LE>()?;
+    if version != VERSION {
+        return Err(invalid_data(format!(
+            "unsupported format version: expected {VERSION} but got {version}"
+        )));
+    }
+    let flags = reader.read_u8()?;
+    if flags & !(FLAG_METADATA | FLAG_VALUES) != 0 {
+        return Err(invalid_data(format!("unknown flags {flags:#04x}")));
+    }
+
+    let len = read_len(reader)?;
+    let mut ops = Operations::default();
+    for index in 0..len {
+        let op = read_op(reader)?;
+        ops.try_insert(op)
+            .map_err(|error| invalid_data(format!("node %{index}: {error}")))?;
+    }
+
+    if flags & FLAG_METADATA != 0 {
+        ops.record_metadata(true);
+        for node in (0..ops.len()).map(NodeId::from) {
+            if let Some(name) = read_optional_string(reader)? {
+                ops.set_name(node, name);
+            }
+            if let Some(group) = read_optional_string(reader)? {
+                ops.set_group(node, group);
+            }
+        }
+    }
+
+    ops.debug_assert_valid_from(0);
+
+    let values = if flags & FLAG_VALUES != 0 {
+        let mut values = Values::new(ops.len());
+        for node in ops.nodes() {
+            values[node] = reader.read_f64::<LE>()?;
+        }
+        Some(values)
+    } else {
+        None
+    };
+
+    Ok((ops, values))
+}
+
+fn write_op(op: Op, writer: &mut impl Write) -> Result<()> {
+    match op {
+        Op::Nullary(nullary) => {
+            writer.write_u8(KIND_NULLARY)?;
+            match nullary {
+                Nullary::Var => writer.write_u8(NULLARY_VAR)?,
+                Nullary::Input => writer.write_u8(NULLARY_INPUT)?,
+                Nullary::Parameter => writer.write_u8(NULLARY_PARAMETER)?,
+                Nullary::Constant(constant) => {
+                    writer.write_u8(NULLARY_CONSTANT)?;
+                    writer.write_f64::<LE>(constant.value())?;
+                }
+            }
+        }
+        Op::Unary(unary, a) => {
+            writer.write_u8(KIND_UNARY)?;
+            writer.write_u8(variant_index(&Unary::ALL, unary))?;
+            writer.write_u64::<LE>(usize::from(a) as u64)?;
+        }
+        Op::Binary(binary, (a, b)) => {
+            writer.write_u8(KIND_BINARY)?;
+            writer.write_u8(variant_index(&Binary::ALL, binary))?;
+            writer.write_u64::<LE>(usize::from(a) as u64)?;
+            writer.write_u64::<LE>(usize::from(b) as u64)?;
+        }
+    }
+    Ok(())
+}
+
+fn read_op(reader: &mut impl Read) -> Result<Op> {
+    let kind = reader.read_u8()?;
+    let variant = reader.read_u8()?;
+    match kind {
+        KIND_NULLARY => {
+            let nullary = match variant {
+                NULLARY_VAR => Nullary::Var,
+                NULLARY_INPUT => Nullary::Input,
+                NULLARY_PARAMETER => Nullary::Parameter,
+                NULLARY_CONSTANT => Nullary::Constant(Constant::new(reader.read_f64::<LE>()?)),
+                _ => return Err(invalid_data(format!("unknown nullary variant {variant}"))),
+            };
+            Ok(Op::Nullary(nullary))
+        }
+        KIND_UNARY => {
+            let unary = *Unary::ALL
+                .get(usize::from(variant))
+                .ok_or_else(|| invalid_data(format!("unknown unary variant {variant}")))?;
+            Ok(Op::Unary(unary, read_node(reader)?))
+        }
+        KIND_BINARY => {
+            let binary = *Binary::ALL
+                .get(usize::from(variant))
+                .ok_or_else(|| invalid_data(format!("unknown binary variant {variant}")))?;
+            Ok(Op::Binary(binary, (read_node(reader)?, read_node(reader)?)))
+        }
+        _ => Err(invalid_data(format!("unknown operation kind {kind}"))),
+    }
+}
+
+fn variant_index<T: PartialEq>(all: &[T], variant: T) -> u8 {
+    all.iter()
+        .position(|item| *item == variant)
+        .expect("ALL contains every variant") as u8
+}
+
+fn read_len(reader: &mut impl Read) -> Result<usize> {
+    let len = reader.read_u64::<LE>()?;
+    usize::try_from(len).map_err(|_| invalid_data(format!("length {len} does not fit in usize")))
+}
+
+fn read_node(reader: &mut impl Read) -> Result<NodeId> {
+    read_len(reader).map(NodeId::from)
+}
+
+fn write_optional_str(value: Option<&str>, writer: &mut impl Write) -> Result<()> {
+    match value {
+        Some(value) => {
+            writer.write_u64::<LE>(value.len() as u64)?;
+            writer.write_all(value.as_bytes())
+        }
+        None => writer.write_u64::<LE>(ABSENT),
+    }
+}
+
+fn read_optional_string(reader: &mut impl Read) -> Result<Option<String>> {
+    let len = reader.read_u64::<LE>()?;
+    if len == ABSENT {
+        return Ok(None);
+    }
+    let mut bytes = Vec::new();
+    reader.take(len).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 != len {
+        return Err(ErrorKind::UnexpectedEof.into());
+    }
+    String::from_utf8(bytes)
+        .map(Some)
+        .map_err(|error| invalid_data(error.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::engine::{Input, Parameter};
+
+    #[test]
+    fn round_trip() {
+        let mut ops = Operations::default();
+        ops.record_metadata(true);
+        let (x, w) = ops.insert((Input, Parameter));
+        ops.set_name(w, "w");
+        ops.set_group(w, "parameters");
+        let c = ops.constant(0.5);
+        let y = ops.insert((w * x + c).tanh().scale_grad(c));
+
+        let mut values = Values::new(ops.len());
+        values[x] = 2.0;
+        values[w] = -0.25;
+        ops.forward(&mut values);
+
+        let mut bytes = Vec::new();
+        write_operations(&ops, Some(&values), &mut bytes).unwrap();
+        let (loaded, loaded_values) = read_operations(&mut Cursor::new(bytes)).unwrap();
+        let loaded_values = loaded_values.unwrap();
+
+        assert_eq!(loaded.iter().collect::<Vec<_>>(), ops.iter().collect::<Vec<_>>());
+        assert_eq!(loaded.name(w), Some("w"));
+        assert_eq!(loaded.group(w), Some("parameters"));
+        assert_eq!(loaded.name(x), None);
+        assert_eq!(loaded_values[y], values[y]);
+
+        // Evaluate the loaded graph without knowing how it was built.
+        let mut fresh = Values::new(loaded.len());
+        for input in loaded.inputs() {
+            fresh[input] = 2.0;
+        }
+        for parameter in loaded.parameters() {
+            fresh[parameter] = loaded_values[parameter];
+        }
+        loaded.forward(&mut fresh);
+        assert_eq!(fresh[y], values[y]);
+    }
+
+    #[test]
+    fn without_metadata_and_values() {
+        let mut ops = Operations::default();
+        let [a, b] = ops.vars();
+        ops.insert(a.pow(b));
+
+        let mut bytes = Vec::new();
+        write_operations(&ops, None, &mut bytes).unwrap();
+        let (loaded, values) = read_operations(&mut Cursor::new(bytes)).unwrap();
+        assert!(values.is_none());
+        assert!(!loaded.is_recording_metadata());
+        assert_eq!(loaded.iter().collect::<Vec<_>>(), ops.iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn reject_forward_reference() {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&MAGIC);
+        bytes.write_u32::<LE>(VERSION).unwrap();
+        bytes.write_u8(0).unwrap();
+        bytes.write_u64::<LE>(2).unwrap();
+        bytes.extend_from_slice(&[KIND_NULLARY, NULLARY_VAR]);
+        bytes.extend_from_slice(&[KIND_UNARY, 0]);
+        bytes.write_u64::<LE>(1).unwrap();
+
+        let error = read_operations(&mut Cursor::new(bytes)).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        assert!(
+            error.to_string().starts_with("node %1: node %1 is out of range"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn reject_unknown_version() {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&MAGIC);
+        bytes.write_u32::<LE>(VERSION + 1).unwrap();
+
+        let error = read_operations(&mut Cursor::new(bytes)).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,232 @@
+use std::fmt;
+
+use crate::engine::{Binary, Constant, Nullary, Op, Operations, Unary};
+
+/// Transcendental functions like `exp` and `ln` are counted as this many
+/// floating point operations.
+const TRANSCENDENTAL_FLOPS: usize = 8;
+
+/// The number of leaves of each kind.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct LeafCounts {
+    pub var: usize,
+    pub input: usize,
+    pub parameter: usize,
+    pub constant: usize,
+}
+
+/// A summary of the size and estimated cost of a graph, see
+/// `Operations::stats`. The `Display` implementation prints it as a table.
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct GraphStats {
+    pub node_count: usize,
+    /// The number of nodes per operation, keyed by `Op::name`. Operations that
+    /// do not occur are left out.
+    pub op_counts: Vec<(&'static str, usize)>,
+    pub leaf_counts: LeafCounts,
+    /// The number of operations on the longest path from a leaf to any node.
+    pub depth: usize,
+    pub max_fan_in: usize,
+    pub max_fan_out: usize,
+    /// Estimated floating point operations for a forward pass.
+    pub forward_flops: usize,
+    /// Estimated floating point operations for a backward pass over the whole
+    /// graph, excluding the forward pass.
+    pub backward_flops: usize,
+    /// The maximum number of values that are alive at the same time during a
+    /// forward pass. A value is alive from the node that defines it up to its
+    /// last use. Values without uses are considered outputs and stay alive.
+    pub peak_live_values: usize,
+}
+
+impl GraphStats {
+    /// The memory needed to store `peak_live_values` values.
+    #[inline]
+    pub fn peak_live_bytes(&self) -> usize {
+        self.peak_live_values * std::mem::size_of::<f64>()
+    }
+}
+
+impl Operations {
+    pub fn stats(&self) -> GraphStats {
+        let mut stats = GraphStats {
+            node_count: self.len(),
+            ..Default::default()
+        };
+
+        let mut op_counts = std::collections::HashMap::<&'static str, usize>::new();
+        let mut depths = vec![0; self.len()];
+        let mut fan_outs = vec![0; self.len()];
+        let mut last_uses = vec![None; self.len()];
+
+        for node in self.nodes() {
+            let op = self[node];
+            *op_counts.entry(op.name()).or_default() += 1;
+
+            match op {
+                Op::Nullary(Nullary::Var) => stats.leaf_counts.var += 1,
+                Op::Nullary(Nullary::Input) => stats.leaf_counts.input += 1,
+                Op::Nullary(Nullary::Parameter) => stats.leaf_counts.parameter += 1,
+                Op::Nullary(Nullary::Constant(_)) => stats.leaf_counts.constant += 1,
+                Op::Unary(..) | Op::Binary(..) => {}
+            }
+
+            let index = usize::from(node);
+            let mut fan_in = 0;
+            for operand in op.operands() {
+                fan_in += 1;
+                depths[index] = depths[index].max(depths[usize::from(operand)] + 1);
+                fan_outs[usize::from(operand)] += 1;
+                last_uses[usize::from(operand)] = Some(index);
+            }
+            stats.max_fan_in = stats.max_fan_in.max(fan_in);
+            stats.depth = stats.depth.max(depths[index]);
+            stats.forward_flops += forward_flops(op);
+            stats.backward_flops += backward_flops(op);
+        }
+
+        stats.max_fan_out = fan_outs.into_iter().max().unwrap_or_default();
+
+        // Count how many values die after each node to find the peak.
+        let mut deaths = vec![0usize; self.len()];
+        for last_use in last_uses.into_iter().flatten() {
+            deaths[last_use] += 1;
+        }
+        let mut live = 0;
+        for dead in deaths {
+            live += 1;
+            stats.peak_live_values = stats.peak_live_values.max(live);
+            live -= dead;
+        }
+
+        let kinds = [
+            Nullary::Var,
+            Nullary::Input,
+            Nullary::Parameter,
+            Nullary::Constant(Constant::new(0.0)),
+        ]
+        .map(Nullary::name)
+        .into_iter()
+        .chain(Unary::ALL.map(Unary::name))
+        .chain(Binary::ALL.map(Binary::name));
+        stats.op_counts = kinds
+            .filter_map(|name| op_counts.get(name).map(|&count| (name, count)))
+            .collect();
+
+        stats
+    }
+}
+
+fn forward_flops(op: Op) -> usize {
+    match op {
+        Op::Nullary(_) => 0,
+        Op::Unary(unary, _) => match unary {
+            Unary::Detach => 0,
+            Unary::Neg | Unary::Recip | Unary::Pow2 | Unary::ReLU | Unary::RoundSTE => 1,
+            Unary::Ln | Unary::Ln1P | Unary::Exp | Unary::Exp2 | Unary::ExpM1 | Unary::TanH => TRANSCENDENTAL_FLOPS,
+        },
+        Op::Binary(binary, _) => match binary {
+            Binary::ScaleGrad => 0,
+            Binary::Add | Binary::Sub | Binary::Mul | Binary::Div | Binary::Max => 1,
+            Binary::Pow => TRANSCENDENTAL_FLOPS,
+        },
+    }
+}
+
+/// The cost of computing the partial derivatives plus a multiply and an add
+/// per operand to accumulate the gradient.
+fn backward_flops(op: Op) -> usize {
+    match op {
+        Op::Nullary(_) => 0,
+        Op::Unary(unary, _) => {
+            let partial = match unary {
+                Unary::Neg | Unary::Exp | Unary::Detach | Unary::RoundSTE => 0,
+                Unary::Pow2 | Unary::Ln | Unary::Exp2 | Unary::ReLU => 1,
+                Unary::Recip | Unary::Ln1P | Unary::TanH => 2,
+                Unary::ExpM1 => TRANSCENDENTAL_FLOPS,
+            };
+            partial + 2
+        }
+        Op::Binary(binary, _) => {
+            let partial = match binary {
+                Binary::Add | Binary::Sub | Binary::Mul | Binary::ScaleGrad => 0,
+                Binary::Max => 1,
+                Binary::Div => 3,
+                Binary::Pow => 2 * TRANSCENDENTAL_FLOPS + 3,
+            };
+            partial + 4
+        }
+    }
+}
+
+impl fmt::Display for GraphStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let rows = [
+            ("nodes", self.node_count),
+            ("depth", self.depth),
+            ("max fan-in", self.max_fan_in),
+            ("max fan-out", self.max_fan_out),
+            ("forward flops", self.forward_flops),
+            ("backward flops", self.backward_flops),
+            ("peak live values", self.peak_live_values),
+            ("peak live bytes", self.peak_live_bytes()),
+        ];
+        for (label, value) in rows {
+            writeln!(f, "{label:<18} {value:>12}")?;
+        }
+        writeln!(f)?;
+        writeln!(f, "{:<18} {:>12}", "op", "count")?;
+        for &(name, count) in &self.op_counts {
+            writeln!(f, "{name:<18} {count:>12}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{Input, Parameter};
+
+    #[test]
+    fn affine_loss() {
+        let mut ops = Operations::default();
+        let (x, y) = ops.insert((Input, Input));
+        let [a, b] = ops.insert([Parameter; 2]);
+        let y_pred = ops.insert(a * x + b);
+        let _loss = ops.insert((y - y_pred).pow_2());
+
+        let stats = ops.stats();
+        assert_eq!(stats.node_count, 8);
+        assert_eq!(
+            stats.op_counts,
+            [
+                ("input", 2),
+                ("parameter", 2),
+                ("pow_2", 1),
+                ("add", 1),
+                ("sub", 1),
+                ("mul", 1)
+            ]
+        );
+        assert_eq!(
+            stats.leaf_counts,
+            LeafCounts {
+                input: 2,
+                parameter: 2,
+                ..Default::default()
+            }
+        );
+        assert_eq!(stats.depth, 4);
+        assert_eq!(stats.max_fan_in, 2);
+        assert_eq!(stats.max_fan_out, 1);
+        assert_eq!(stats.forward_flops, 4);
+        assert_eq!(stats.backward_flops, 4 + 4 + 4 + 3);
+        // All four leaves are alive when the first operation runs.
+        assert_eq!(stats.peak_live_values, 5);
+
+        let table = stats.to_string();
+        assert!(table.contains("peak live bytes"));
+        assert!(table.contains("pow_2"));
+    }
+}
@@ -0,0 +1,340 @@
+//! Structural hashing and equivalence of graphs, for deduplicating graphs that
+//! were built by different code.
+use crate::engine::{Binary, NodeId, Nullary, Op, Operations, Unary, check_node};
+
+/// Controls which differences `Operations::structural_hash` and
+/// `Operations::is_equivalent` ignore. Metadata is always ignored.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct Equivalence {
+    /// Compare the shape of the graphs rather than the indices of their nodes,
+    /// so graphs built in a different order or with unrelated nodes in between
+    /// are equivalent.
+    pub ignore_numbering: bool,
+    /// Treat `a + b` as `b + a` and `a * b` as `b * a`.
+    pub commutative: bool,
+}
+
+impl Equivalence {
+    /// Only graphs with the same nodes at the same indices are equivalent.
+    pub const EXACT: Self = Self {
+        ignore_numbering: false,
+        commutative: false,
+    };
+
+    /// Graphs that compute the same expressions from the same kinds of leaves
+    /// are equivalent.
+    pub const STRUCTURAL: Self = Self {
+        ignore_numbering: true,
+        commutative: true,
+    };
+
+    /// `Max` is not included because its gradient goes to the first operand on
+    /// ties, so swapping the operands changes the backward pass.
+    #[inline]
+    fn is_commutative(self, binary: Binary) -> bool {
+        self.commutative && matches!(binary, Binary::Add | Binary::Mul)
+    }
+}
+
+/// The finalizer of SplitMix64. The hashes have to be stable across runs and
+/// platforms to be usable as cache keys, which rules out `DefaultHasher`.
+#[inline]
+fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+#[inline]
+fn combine(hash: u64, value: u64) -> u64 {
+    mix(hash.rotate_left(5) ^ value)
+}
+
+fn position<T: PartialEq>(all: &[T], item: T) -> u64 {
+    all.iter()
+        .position(|other| *other == item)
+        .expect("ALL contains every variant") as u64
+}
+
+fn op_code(op: Op) -> u64 {
+    match op {
+        Op::Nullary(Nullary::Var) => 0,
+        Op::Nullary(Nullary::Input) => 1,
+        Op::Nullary(Nullary::Parameter) => 2,
+        Op::Nullary(Nullary::Constant(_)) => 3,
+        Op::Unary(unary, _) => 0x100 + position(&Unary::ALL, unary),
+        Op::Binary(binary, _) => 0x200 + position(&Binary::ALL, binary),
+    }
+}
+
+impl Operations {
+    /// Marks `roots` and every node they depend on.
+    #[track_caller]
+    fn cone(&self, roots: &[NodeId]) -> Vec<bool> {
+        let mut in_cone = vec![false; self.len()];
+        for &root in roots {
+            if let Err(error) = check_node(root, self.len()) {
+                panic!("{}", error.display(self));
+            }
+            in_cone[usize::from(root)] = true;
+        }
+        for node in self.nodes().rev() {
+            if in_cone[usize::from(node)] {
+                for operand in self[node].operands() {
+                    in_cone[usize::from(operand)] = true;
+                }
+            }
+        }
+        in_cone
+    }
+
+    /// Hashes every node in the cone of `roots`. Nodes outside of the cone get
+    /// a hash of zero.
+    fn node_hashes(&self, roots: &[NodeId], equivalence: Equivalence) -> Vec<u64> {
+        let in_cone = self.cone(roots);
+        let mut hashes = vec![0; self.len()];
+        for node in self.nodes().filter(|&node| in_cone[usize::from(node)]) {
+            let op = self[node];
+            let mut hash = mix(op_code(op));
+            match op {
+                Op::Nullary(Nullary::Constant(constant)) => hash = combine(hash, constant.value().to_bits()),
+                Op::Nullary(_) => {}
+                Op::Unary(_, a) => hash = combine(hash, hashes[usize::from(a)]),
+                Op::Binary(binary, (a, b)) => {
+                    let (mut a, mut b) = (hashes[usize::from(a)], hashes[usize::from(b)]);
+                    if equivalence.is_commutative(binary) && a > b {
+                        std::mem::swap(&mut a, &mut b);
+                    }
+                    hash = combine(combine(hash, a), b);
+                }
+            }
+            if !equivalence.ignore_numbering {
+                hash = combine(hash, usize::from(node) as u64);
+            }
+            hashes[usize::from(node)] = hash;
+        }
+        hashes
+    }
+
+    /// Hashes the computation of `roots`, in order, such that equivalent graphs
+    /// have the same hash. The hash is stable across runs and platforms.
+    ///
+    /// Leaves of the same kind are not told apart and sharing is not taken into
+    /// account, so graphs with the same hash are not necessarily equivalent.
+    /// Use `is_equivalent` to make sure.
+    ///
+    /// Panics if a root is not a node in this graph.
+    #[track_caller]
+    pub fn structural_hash(&self, roots: &[NodeId], equivalence: Equivalence) -> u64 {
+        let hashes = self.node_hashes(roots, equivalence);
+        roots.iter().fold(mix(roots.len() as u64), |hash, &root| {
+            combine(hash, hashes[usize::from(root)])
+        })
+    }
+
+    /// Returns whether `roots` compute the same as `other_roots` in `other`.
+    /// That is the case when the nodes in their cones can be put into a one to
+    /// one correspondence that maps the roots onto each other in order and
+    /// preserves operations and operands.
+    ///
+    /// Panics if a root is not a node in its graph.
+    #[track_caller]
+    pub fn is_equivalent(
+        &self,
+        roots: &[NodeId],
+        other: &Operations,
+        other_roots: &[NodeId],
+        equivalence: Equivalence,
+    ) -> bool {
+        let hashes = self.node_hashes(roots, equivalence);
+        let other_hashes = other.node_hashes(other_roots, equivalence);
+        if roots.len() != other_roots.len() {
+            return false;
+        }
+
+        let mut matcher = Matcher {
+            graphs: [self, other],
+            hashes: [hashes, other_hashes],
+            equivalence,
+            mapping: [vec![None; self.len()], vec![None; other.len()]],
+            log: Vec::new(),
+        };
+        let pending = std::iter::zip(roots.iter().copied(), other_roots.iter().copied())
+            .rev()
+            .collect();
+        matcher.solve(pending)
+    }
+}
+
+/// Searches for a one to one correspondence between the nodes of two graphs.
+/// Commutative operations are the only choice points, where both orders of
+/// the operands are tried. Comparing node hashes first prunes nearly all wrong
+/// choices, but the search can take exponential time in contrived cases.
+struct Matcher<'a> {
+    graphs: [&'a Operations; 2],
+    hashes: [Vec<u64>; 2],
+    equivalence: Equivalence,
+    mapping: [Vec<Option<NodeId>>; 2],
+    /// The nodes of the first graph in the order they were mapped, to undo
+    /// mappings when backtracking.
+    log: Vec<NodeId>,
+}
+
+impl Matcher<'_> {
+    fn undo(&mut self, len: usize) {
+        for node in self.log.drain(len..) {
+            let other = self.mapping[0][usize::from(node)]
+                .take()
+                .expect("logged nodes are mapped");
+            self.mapping[1][usize::from(other)] = None;
+        }
+    }
+
+    /// Maps every pair in `pending`, which is used as a stack, along with their
+    /// operands. Leaves the mapping unchanged if that is not possible.
+    fn solve(&mut self, mut pending: Vec<(NodeId, NodeId)>) -> bool {
+        let start = self.log.len();
+        while let Some((a, b)) = pending.pop() {
+            match (self.mapping[0][usize::from(a)], self.mapping[1][usize::from(b)]) {
+                (Some(mapped), _) if mapped == b => continue,
+                (None, None) => {}
+                _ => {
+                    self.undo(start);
+                    return false;
+                }
+            }
+            if self.hashes[0][usize::from(a)] != self.hashes[1][usize::from(b)]
+                || !self.equivalence.ignore_numbering && a != b
+            {
+                self.undo(start);
+                return false;
+            }
+            self.mapping[0][usize::from(a)] = Some(b);
+            self.mapping[1][usize::from(b)] = Some(a);
+            self.log.push(a);
+
+            match (self.graphs[0][a], self.graphs[1][b]) {
+                (Op::Nullary(x), Op::Nullary(y)) if x == y => {}
+                (Op::Unary(x, a0), Op::Unary(y, b0)) if x == y => pending.push((a0, b0)),
+                (Op::Binary(x, (a0, a1)), Op::Binary(y, (b0, b1))) if x == y => {
+                    if self.equivalence.is_commutative(x) {
+                        let mut straight = pending.clone();
+                        straight.extend([(a1, b1), (a0, b0)]);
+                        if self.solve(straight) {
+                            return true;
+                        }
+                        pending.extend([(a0, b1), (a1, b0)]);
+                    } else {
+                        pending.extend([(a1, b1), (a0, b0)]);
+                    }
+                }
+                _ => {
+                    self.undo(start);
+                    return false;
+                }
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        engine::{Input, Parameter},
+        nn::{self, FullyConnectedLayer},
+    };
+
+    #[test]
+    fn commutative_operands() {
+        let mut ops = Operations::default();
+        let [x, y] = ops.vars();
+        let a = ops.insert(x + y * x);
+        let b = ops.insert(x * y + x);
+        let c = ops.insert(x * x + x);
+
+        assert!(ops.is_equivalent(&[a], &ops, &[b], Equivalence::STRUCTURAL));
+        assert_eq!(
+            ops.structural_hash(&[a], Equivalence::STRUCTURAL),
+            ops.structural_hash(&[b], Equivalence::STRUCTURAL)
+        );
+        let ordered = Equivalence {
+            commutative: false,
+            ..Equivalence::STRUCTURAL
+        };
+        assert!(!ops.is_equivalent(&[a], &ops, &[b], ordered));
+        assert!(!ops.is_equivalent(&[a], &ops, &[c], Equivalence::STRUCTURAL));
+
+        // The gradient of `max` goes to the first operand on ties.
+        let d = ops.insert(x.max(y) - x);
+        let e = ops.insert(y.max(x) - x);
+        assert!(!ops.is_equivalent(&[d], &ops, &[e], Equivalence::STRUCTURAL));
+    }
+
+    #[test]
+    fn backtracking() {
+        // The first order tried for the addition maps `x` to `y`, which only
+        // fails when the exponent is compared.
+        let mut ops = Operations::default();
+        let [x, y] = ops.vars();
+        let a = ops.insert((x + y) * x.exp());
+        let b = ops.insert((y + x) * x.exp());
+        assert!(ops.is_equivalent(&[a], &ops, &[b], Equivalence::STRUCTURAL));
+    }
+
+    #[test]
+    fn leaf_kinds_and_constants() {
+        let mut ops = Operations::default();
+        let (x, w) = ops.insert((Input, Parameter));
+        let one = ops.constant(1.0);
+        let two = ops.constant(2.0);
+        let a = ops.insert(x + one);
+        let b = ops.insert(w + one);
+        let c = ops.insert(x + two);
+        let d = ops.insert(x + one);
+
+        assert!(!ops.is_equivalent(&[a], &ops, &[b], Equivalence::STRUCTURAL));
+        assert!(!ops.is_equivalent(&[a], &ops, &[c], Equivalence::STRUCTURAL));
+        assert!(ops.is_equivalent(&[a], &ops, &[d], Equivalence::STRUCTURAL));
+        assert!(!ops.is_equivalent(&[a], &ops, &[d], Equivalence::EXACT));
+        assert!(ops.is_equivalent(&[a], &ops, &[a], Equivalence::EXACT));
+    }
+
+    fn fully_connected_layer(ops: &mut Operations) -> Vec<NodeId> {
+        let input = nn::input_layer_vec((nn::B(2), nn::O(3)), ops);
+        let layer = FullyConnectedLayer::new(
+            input.as_deref().reindex(nn::batched_output_to_input),
+            nn::O(2),
+            ops,
+            |x| x.relu(),
+        );
+        layer.outputs().iter().copied().collect()
+    }
+
+    #[test]
+    fn fully_connected_layer_shape() {
+        let mut ops1 = Operations::default();
+        let outputs1 = fully_connected_layer(&mut ops1);
+
+        let mut ops2 = Operations::default();
+        let [unrelated] = ops2.vars();
+        ops2.insert(unrelated.exp());
+        let outputs2 = fully_connected_layer(&mut ops2);
+
+        assert!(ops1.is_equivalent(&outputs1, &ops2, &outputs2, Equivalence::STRUCTURAL));
+        assert_eq!(
+            ops1.structural_hash(&outputs1, Equivalence::STRUCTURAL),
+            ops2.structural_hash(&outputs2, Equivalence::STRUCTURAL)
+        );
+        assert_ne!(
+            ops1.structural_hash(&outputs1, Equivalence::EXACT),
+            ops2.structural_hash(&outputs2, Equivalence::EXACT)
+        );
+
+        // Swapping two outputs breaks the correspondence of the roots.
+        let mut swapped = outputs2.clone();
+        swapped.swap(0, 1);
+        assert!(!ops1.is_equivalent(&outputs1, &ops2, &swapped, Equivalence::STRUCTURAL));
+    }
+}
@@ -1,12 +1,17 @@
-use crate::engine::{Expr, Insertable, NodeId, Op, Operations};
+use crate::{
+    deref_slice::DerefSlice,
+    engine::{Error, Expr, Insertable, NodeId, Op, Operations},
+    view::{IndexTuple, View},
+};
 
+#[derive(Copy, Clone)]
 pub struct Unary<O, A>(O, A);
 
 impl<O: unary::Variant, A: Insertable<Output = NodeId>> Insertable for Unary<O, A> {
     type Output = NodeId;
 
-    fn insert_into(self, ops: &mut Operations) -> Self::Output {
-        Op::Unary(O::OP, self.1.insert_into(ops)).insert_into(ops)
+    fn try_insert_into(self, ops: &mut Operations) -> Result<Self::Output, Error> {
+        Op::Unary(O::OP, self.1.try_insert_into(ops)?).try_insert_into(ops)
     }
 }
 
@@ -22,8 +27,11 @@ macro_rules! call_with_unary_variants {
         $macro!(ExpM1, exp_m1);
         $macro!(TanH, tanh);
         $macro!(ReLU, relu);
+        $macro!(Detach, detach);
+        $macro!(RoundSTE, round_ste);
     };
 }
+pub(crate) use call_with_unary_variants;
 
 pub mod unary {
     use crate::engine;
@@ -34,6 +42,7 @@ pub mod unary {
 
     macro_rules! impl_struct {
         ($V:ident, $v:ident) => {
+            #[derive(Copy, Clone)]
             pub struct $V;
         };
     }
@@ -79,13 +88,14 @@ macro_rules! impl_unary_op {
 }
 call_with_unary_variants!(impl_unary_op);
 
+#[derive(Copy, Clone)]
 pub struct Binary<O, A>(O, A);
 
 impl<O: binary::Variant, A: Insertable<Output = (NodeId, NodeId)>> Insertable for Binary<O, A> {
     type Output = NodeId;
 
-    fn insert_into(self, ops: &mut Operations) -> Self::Output {
-        Op::Binary(O::OP, self.1.insert_into(ops)).insert_into(ops)
+    fn try_insert_into(self, ops: &mut Operations) -> Result<Self::Output, Error> {
+        Op::Binary(O::OP, self.1.try_insert_into(ops)?).try_insert_into(ops)
     }
 }
 
@@ -96,8 +106,11 @@ macro_rules! call_with_binary_variants {
         $macro!(Mul, mul);
         $macro!(Div, div);
         $macro!(Pow, pow);
+        $macro!(ScaleGrad, scale_grad);
+        $macro!(Max, max);
     };
 }
+pub(crate) use call_with_binary_variants;
 
 pub mod binary {
     use crate::engine;
@@ -108,6 +121,7 @@ pub mod binary {
 
     macro_rules! impl_struct {
         ($V:ident, $v:ident) => {
+            #[derive(Copy, Clone)]
             pub struct $V;
         };
     }
@@ -162,9 +176,180 @@ macro_rules! impl_binary_op {
 }
 call_with_binary_variants!(impl_binary_op);
 
+/// An operand of an elementwise operation on a view of expressions, which is
+/// either a view with the same shape or a single expression that is combined
+/// with every element.
+pub trait Elementwise<X> {
+    type Item: Copy;
+
+    /// Panics if the operand cannot be combined with a view of `shape`.
+    fn check_shape(&self, shape: X);
+
+    /// The operand of the element at the flat `index`.
+    fn element(&self, index: usize) -> Expr<Self::Item>;
+}
+
+impl<A, X, T> Elementwise<X> for View<A, X>
+where
+    A: DerefSlice<Item = Expr<T>>,
+    X: IndexTuple + PartialEq,
+    T: Copy,
+{
+    type Item = T;
+
+    #[inline]
+    #[track_caller]
+    fn check_shape(&self, shape: X) {
+        assert!(self.shape() == shape, "elementwise operands have different shapes");
+    }
+
+    #[inline]
+    fn element(&self, index: usize) -> Expr<T> {
+        self.data()[index]
+    }
+}
+
+impl<T: Copy, X> Elementwise<X> for Expr<T> {
+    type Item = T;
+
+    #[inline]
+    fn check_shape(&self, _shape: X) {}
+
+    #[inline]
+    fn element(&self, _index: usize) -> Expr<T> {
+        *self
+    }
+}
+
+impl<A, X, T> View<A, X>
+where
+    A: DerefSlice<Item = Expr<T>>,
+    X: IndexTuple + PartialEq,
+    T: Copy,
+{
+    #[track_caller]
+    fn zip_with<R, U, F>(&self, rhs: R, mut f: F) -> View<Vec<U>, X>
+    where
+        R: Elementwise<X>,
+        F: FnMut(Expr<T>, Expr<R::Item>) -> U,
+    {
+        rhs.check_shape(self.shape());
+        let data = self.iter().enumerate().map(|(index, &a)| f(a, rhs.element(index)));
+        View::new(data.collect(), self.shape())
+    }
+}
+
+/// A view of expressions can be inserted to obtain a view of the nodes.
+impl<I: Insertable, X: IndexTuple> Insertable for View<Vec<I>, X> {
+    type Output = View<Vec<I::Output>, X>;
+
+    #[inline]
+    fn try_insert_into(self, ops: &mut Operations) -> Result<Self::Output, Error> {
+        let shape = self.shape();
+        let outputs = self
+            .into_data()
+            .into_iter()
+            .map(|item| ops.try_insert(item))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(View::new(outputs, shape))
+    }
+}
+
+macro_rules! impl_view_unary_op {
+    (Neg, neg) => {
+        impl<A, X, T> std::ops::Neg for View<A, X>
+        where
+            A: DerefSlice<Item = Expr<T>>,
+            X: IndexTuple,
+            T: Copy,
+        {
+            type Output = View<Vec<Expr<Neg<T>>>, X>;
+
+            fn neg(self) -> Self::Output {
+                self.map(|&a| -a)
+            }
+        }
+    };
+    ($V:ident, $v:ident) => {
+        impl<A, X, T> View<A, X>
+        where
+            A: DerefSlice<Item = Expr<T>>,
+            X: IndexTuple,
+            T: Copy,
+        {
+            pub fn $v(&self) -> View<Vec<Expr<$V<T>>>, X> {
+                self.map(|&a| a.$v())
+            }
+        }
+    };
+}
+call_with_unary_variants!(impl_view_unary_op);
+
+macro_rules! impl_view_binary_op {
+    (Add, add) => {
+        impl_view_binary_op!(@trait Add, add);
+    };
+    (Sub, sub) => {
+        impl_view_binary_op!(@trait Sub, sub);
+    };
+    (Mul, mul) => {
+        impl_view_binary_op!(@trait Mul, mul);
+    };
+    (Div, div) => {
+        impl_view_binary_op!(@trait Div, div);
+    };
+    ($V:ident, $v:ident) => {
+        impl<A, X, T> View<A, X>
+        where
+            A: DerefSlice<Item = Expr<T>>,
+            X: IndexTuple + PartialEq,
+            T: Copy,
+        {
+            #[track_caller]
+            pub fn $v<R: Elementwise<X>>(&self, rhs: R) -> View<Vec<Expr<$V<T, R::Item>>>, X> {
+                self.zip_with(rhs, |a, b| a.$v(b))
+            }
+        }
+    };
+    (@trait $V:ident, $v:ident) => {
+        impl<A, X, T, R> std::ops::$V<R> for View<A, X>
+        where
+            A: DerefSlice<Item = Expr<T>>,
+            X: IndexTuple + PartialEq,
+            T: Copy,
+            R: Elementwise<X>,
+        {
+            type Output = View<Vec<Expr<$V<T, R::Item>>>, X>;
+
+            #[track_caller]
+            fn $v(self, rhs: R) -> Self::Output {
+                self.zip_with(rhs, |a, b| std::ops::$V::$v(a, b))
+            }
+        }
+
+        impl<A, X, T, U> std::ops::$V<View<A, X>> for Expr<U>
+        where
+            A: DerefSlice<Item = Expr<T>>,
+            X: IndexTuple,
+            T: Copy,
+            U: Copy,
+        {
+            type Output = View<Vec<Expr<$V<U, T>>>, X>;
+
+            fn $v(self, rhs: View<A, X>) -> Self::Output {
+                rhs.map(|&b| std::ops::$V::$v(self, b))
+            }
+        }
+    };
+}
+call_with_binary_variants!(impl_view_binary_op);
+
 #[cfg(test)]
 pub mod tests {
-    use crate::engine::Operations;
+    use crate::{
+        engine::{Operations, Values},
+        view::View,
+    };
 
     #[allow(unused)]
     fn should_compile() {
@@ -173,5 +358,40 @@ pub mod tests {
         let _c = ops.insert(a + b);
         let _d = ops.insert(a * b);
         let _e = ops.insert(a.pow(b));
+        let _f = ops.insert(a.round_ste().detach().scale_grad(b));
+    }
+
+    #[test]
+    fn elementwise_views() {
+        let ops = &mut Operations::default();
+        let a = View::new(ops.vars_vec(6), (2, 3));
+        let b = View::new(ops.vars_vec(6), (2, 3));
+        let s = ops.var();
+
+        let c = ops.insert((a.as_deref() + b.as_deref()).relu() * s);
+        let d = ops.insert(s - a.as_deref().pow(b.as_deref()));
+        assert_eq!(c.shape(), (2, 3));
+
+        let mut values = Values::new(ops.len());
+        for (index, (&a, &b)) in a.iter().zip(b.iter()).enumerate() {
+            values[a] = index as f64 - 2.0;
+            values[b] = 2.0;
+        }
+        values[s] = 3.0;
+        ops.forward(&mut values);
+        for (index, (&c, &d)) in c.iter().zip(d.iter()).enumerate() {
+            let a = index as f64 - 2.0;
+            assert_eq!(values[c], (a + 2.0).max(0.0) * 3.0);
+            assert_eq!(values[d], 3.0 - a.powf(2.0));
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "different shapes")]
+    fn elementwise_shape_mismatch() {
+        let ops = &mut Operations::default();
+        let a = View::new(ops.vars_vec(6), (2, 3));
+        let b = View::new(ops.vars_vec(6), (3, 2));
+        let _ = a.as_deref() + b.as_deref();
     }
 }
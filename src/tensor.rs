@@ -1,53 +1,463 @@
-// TODO: make this work I guess, I started with Tensor and ended up writing the View abstraction (multi-dimensional contiguous array view). 
-
-// pub trait Zero {
-//     const ZERO: Self;
-// }
-
-// pub struct Tensor<T, X> {
-//     data: Box<[T]>,
-//     len: X,
-// }
-
-// impl<T, X0, X1, X2> std::ops::Mul<&Tensor<T, (X1, X2)>> for &Tensor<T, (X0, X1)> where T: std::ops::Mul<Output = T> + std::ops::AddAssign + Zero + Copy, X0: Index, X1: Index, X2: Index {
-//     type Output = Tensor<T, (X0, X2)>;
-
-//     fn mul(self, rhs: &Tensor<T, (X1, X2)>) -> Self::Output {
-//         assert_eq!(self.len.1.into(), rhs.len.0.into());
-//         let len = (self.len.0, rhs.len.1);
-//         let mut data = Vec::with_capacity(len.product());
-//         for x2 in rhs.len.1.indices() {
-//             for x0 in self.len.0.indices() {
-//                 let mut sum = T::ZERO;
-//                 for x1 in self.len.1.indices() {
-//                     sum += self[(x0, x1)] * rhs[(x1, x2)];
-//                 }
-//                 data[len.flatten((x0, x2))] = sum;
-//             }
-//         }
-//         Tensor {
-//             data: data.into_boxed_slice(), len
-//         }
-//     }
-// }
-
-// impl<T, X> std::ops::Index<X> for Tensor<T, X> where X: IndexTuple {
-//     type Output = T;
-
-//     fn index(&self, index: X) -> &Self::Output {
-//         &self.data[self.len.flatten(index)]
-//     }
-// }
-
-// impl<T, X> std::ops::IndexMut<X> for Tensor<T, X> where X: IndexTuple {
-//     fn index_mut(&mut self, index: X) -> &mut Self::Output {
-//         &mut self.data[self.len.flatten(index)]
-//     }
-// }
-
-// impl<T, X0, X1> Tensor<T, (X0, X1)> where T: Zero + Copy, X0: Index, X1: Index {
-//     pub fn zeros(len: (X0, X1)) -> Self {
-//         let data = vec![T::ZERO; len.product()].into_boxed_slice();
-//         Self { data, len }
-//     }
-// }
+//! An owned n-dimensional array on top of `View`.
+//!
+//! The same type holds data, like `Tensor<f64, (Sample, Feature)>`, and graph
+//! nodes, like `Tensor<NodeId, (Output, Input)>`. Arithmetic on tensors of
+//! `f64` computes the result right away. Arithmetic on tensors of nodes builds
+//! expressions which are turned into a tensor of nodes by `Operations::insert`,
+//! and `Operations::matmul` multiplies matrices of nodes.
+use std::ops::{Add, Div, Mul, Sub};
+
+use crate::{
+    deref_slice::DerefSlice,
+    engine::{Error, Insertable, NodeId, Operations},
+    view::{Index, IndexTuple, View},
+};
+
+pub trait Zero {
+    const ZERO: Self;
+}
+
+pub trait One {
+    const ONE: Self;
+}
+
+impl Zero for f64 {
+    const ZERO: Self = 0.0;
+}
+
+impl One for f64 {
+    const ONE: Self = 1.0;
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tensor<T, X> {
+    view: View<Vec<T>, X>,
+}
+
+impl<T, X> Tensor<T, X>
+where
+    X: IndexTuple,
+{
+    /// `data` is in the order `IndexTuple::flatten` stores elements in.
+    ///
+    /// Panics if the length of `data` is not the product of `shape`.
+    #[track_caller]
+    pub fn from_vec(data: Vec<T>, shape: X) -> Self {
+        Self {
+            view: View::new(data, shape),
+        }
+    }
+
+    /// Calls `f` for every index in the order of `IndexTuple::indices`.
+    pub fn from_fn<F>(shape: X, mut f: F) -> Self
+    where
+        F: FnMut(X) -> T,
+    {
+        let mut elements: Vec<_> = shape.indices().map(|index| (shape.flatten(index), f(index))).collect();
+        elements.sort_unstable_by_key(|&(flat, _)| flat);
+        Self::from_vec(elements.into_iter().map(|(_, element)| element).collect(), shape)
+    }
+
+    /// A tensor with every element set to `value`, like the same constant
+    /// node everywhere.
+    pub fn full(shape: X, value: T) -> Self
+    where
+        T: Clone,
+    {
+        Self::from_vec(vec![value; shape.product()], shape)
+    }
+
+    pub fn zeros(shape: X) -> Self
+    where
+        T: Zero + Clone,
+    {
+        Self::full(shape, T::ZERO)
+    }
+
+    pub fn ones(shape: X) -> Self
+    where
+        T: One + Clone,
+    {
+        Self::full(shape, T::ONE)
+    }
+
+    #[inline]
+    pub fn shape(&self) -> X {
+        self.view.shape()
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.view.data().len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.view.data().is_empty()
+    }
+
+    /// The elements in the order `IndexTuple::flatten` stores them.
+    #[inline]
+    pub fn as_slice(&self) -> &[T] {
+        self.view.data()
+    }
+
+    #[inline]
+    pub fn as_mut_slice(&mut self) -> &mut [T] {
+        self.view.as_deref_mut().into_data()
+    }
+
+    #[inline]
+    pub fn into_vec(self) -> Vec<T> {
+        self.view.into_data()
+    }
+
+    /// Borrows the tensor as a view, for example to use the elementwise syntax
+    /// of views or to pass it to code that takes views.
+    #[inline]
+    pub fn view(&self) -> View<&[T], X> {
+        self.view.as_deref()
+    }
+
+    #[inline]
+    pub fn view_mut(&mut self) -> View<&mut [T], X> {
+        self.view.as_deref_mut()
+    }
+
+    #[inline]
+    pub fn into_view(self) -> View<Vec<T>, X> {
+        self.view
+    }
+
+    #[inline]
+    pub fn iter(&self) -> std::slice::Iter<'_, T> {
+        self.as_slice().iter()
+    }
+
+    #[inline]
+    pub fn iter_mut(&mut self) -> std::slice::IterMut<'_, T> {
+        self.as_mut_slice().iter_mut()
+    }
+
+    /// The elements with their indices, in the order of `IndexTuple::indices`.
+    pub fn iter_enumerate(&self) -> impl Iterator<Item = (X, &T)> {
+        self.shape().indices().map(move |index| (index, &self[index]))
+    }
+
+    pub fn map<U, F>(&self, f: F) -> Tensor<U, X>
+    where
+        F: FnMut(&T) -> U,
+    {
+        self.view.map(f).into()
+    }
+
+    /// Combines the corresponding elements of `self` and `rhs`.
+    ///
+    /// Panics if the shapes differ.
+    #[track_caller]
+    pub fn zip_map<U, V, F>(&self, rhs: &Tensor<U, X>, mut f: F) -> Tensor<V, X>
+    where
+        X: PartialEq,
+        F: FnMut(&T, &U) -> V,
+    {
+        assert!(self.shape() == rhs.shape(), "tensors have different shapes");
+        let data = self.iter().zip(rhs.iter()).map(|(a, b)| f(a, b)).collect();
+        Tensor::from_vec(data, self.shape())
+    }
+
+    /// Gives the tensor a shape with the same number of elements, keeping the
+    /// elements in storage order.
+    ///
+    /// Panics if the number of elements differs.
+    #[track_caller]
+    pub fn reshape<Y: IndexTuple>(self, shape: Y) -> Tensor<T, Y> {
+        Tensor::from_vec(self.into_vec(), shape)
+    }
+
+    pub fn fold<B, F>(&self, init: B, f: F) -> B
+    where
+        F: FnMut(B, &T) -> B,
+    {
+        self.iter().fold(init, f)
+    }
+
+    /// The sum of the elements, or zero if there are none. Tensors of nodes
+    /// are summed with `Operations::sum`.
+    pub fn sum(&self) -> T
+    where
+        T: Zero + Add<Output = T> + Copy,
+    {
+        self.fold(T::ZERO, |acc, &x| acc + x)
+    }
+
+    /// The product of the elements, or one if there are none.
+    pub fn product(&self) -> T
+    where
+        T: One + Mul<Output = T> + Copy,
+    {
+        self.fold(T::ONE, |acc, &x| acc * x)
+    }
+}
+
+impl<X: IndexTuple> Tensor<f64, X> {
+    /// The arithmetic mean of the elements, which is NaN if there are none.
+    pub fn mean(&self) -> f64 {
+        self.sum() / self.len() as f64
+    }
+
+    /// The largest element, or negative infinity if there are none.
+    pub fn max(&self) -> f64 {
+        self.fold(f64::NEG_INFINITY, |acc, &x| acc.max(x))
+    }
+
+    /// The smallest element, or infinity if there are none.
+    pub fn min(&self) -> f64 {
+        self.fold(f64::INFINITY, |acc, &x| acc.min(x))
+    }
+}
+
+impl<T, X0, X1> Tensor<T, (X0, X1)>
+where
+    X0: Index,
+    X1: Index,
+{
+    pub fn transpose(&self) -> Tensor<T, (X1, X0)>
+    where
+        T: Clone,
+    {
+        let (x0, x1) = self.shape();
+        Tensor::from_fn((x1, x0), |(i1, i0)| self[(i0, i1)].clone())
+    }
+
+    /// The elements of row `i0`.
+    pub fn row(&self, i0: X0) -> impl Iterator<Item = &T> {
+        self.shape().1.indices().map(move |i1| &self[(i0, i1)])
+    }
+
+    /// The matrix product of `self` and `rhs`. Matrices of nodes are
+    /// multiplied with `Operations::matmul`.
+    ///
+    /// Panics if the number of columns of `self` differs from the number of
+    /// rows of `rhs`.
+    #[track_caller]
+    pub fn matmul<X2>(&self, rhs: &Tensor<T, (X1, X2)>) -> Tensor<T, (X0, X2)>
+    where
+        T: Zero + Add<Output = T> + Mul<Output = T> + Copy,
+        X2: Index,
+    {
+        let ((x0, x1), (rhs_x1, x2)) = (self.shape(), rhs.shape());
+        assert!(x1 == rhs_x1, "inner dimensions of a matrix product differ");
+        Tensor::from_fn((x0, x2), |(i0, i2)| {
+            x1.indices()
+                .fold(T::ZERO, |acc, i1| acc + self[(i0, i1)] * rhs[(i1, i2)])
+        })
+    }
+}
+
+impl<T, X> From<View<Vec<T>, X>> for Tensor<T, X> {
+    #[inline]
+    fn from(view: View<Vec<T>, X>) -> Self {
+        Self { view }
+    }
+}
+
+impl<T, X> std::ops::Index<X> for Tensor<T, X>
+where
+    X: IndexTuple,
+{
+    type Output = T;
+
+    #[inline]
+    fn index(&self, index: X) -> &Self::Output {
+        &self.view[index]
+    }
+}
+
+impl<T, X> std::ops::IndexMut<X> for Tensor<T, X>
+where
+    X: IndexTuple,
+{
+    #[inline]
+    fn index_mut(&mut self, index: X) -> &mut Self::Output {
+        &mut self.view[index]
+    }
+}
+
+impl<'a, T, X> IntoIterator for &'a Tensor<T, X>
+where
+    X: IndexTuple,
+{
+    type Item = &'a T;
+
+    type IntoIter = std::slice::Iter<'a, T>;
+
+    #[inline]
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<T, X> IntoIterator for Tensor<T, X>
+where
+    X: IndexTuple,
+{
+    type Item = T;
+
+    type IntoIter = std::vec::IntoIter<T>;
+
+    #[inline]
+    fn into_iter(self) -> Self::IntoIter {
+        self.into_vec().into_iter()
+    }
+}
+
+/// A tensor of expressions can be inserted to obtain a tensor of nodes.
+impl<I: Insertable, X: IndexTuple> Insertable for Tensor<I, X> {
+    type Output = Tensor<I::Output, X>;
+
+    #[inline]
+    fn try_insert_into(self, ops: &mut Operations) -> Result<Self::Output, Error> {
+        self.view.try_insert_into(ops).map(Tensor::from)
+    }
+}
+
+macro_rules! impl_tensor_op {
+    ($V:ident, $v:ident) => {
+        /// Panics if the shapes differ.
+        impl<T, U, X> $V<&Tensor<U, X>> for &Tensor<T, X>
+        where
+            T: $V<U> + Copy,
+            U: Copy,
+            X: IndexTuple + PartialEq,
+        {
+            type Output = Tensor<T::Output, X>;
+
+            #[inline]
+            #[track_caller]
+            fn $v(self, rhs: &Tensor<U, X>) -> Self::Output {
+                self.zip_map(rhs, |&a, &b| a.$v(b))
+            }
+        }
+
+        impl<T, U, X> $V<Tensor<U, X>> for Tensor<T, X>
+        where
+            T: $V<U> + Copy,
+            U: Copy,
+            X: IndexTuple + PartialEq,
+        {
+            type Output = Tensor<T::Output, X>;
+
+            #[inline]
+            #[track_caller]
+            fn $v(self, rhs: Tensor<U, X>) -> Self::Output {
+                (&self).$v(&rhs)
+            }
+        }
+    };
+}
+impl_tensor_op!(Add, add);
+impl_tensor_op!(Sub, sub);
+impl_tensor_op!(Mul, mul);
+impl_tensor_op!(Div, div);
+
+impl Operations {
+    /// The matrix product of two matrices of nodes, where every element is a
+    /// sum of products as in `Operations::dot`.
+    ///
+    /// Panics if the number of columns of `a` differs from the number of rows
+    /// of `b` or if a node is not in the graph.
+    #[track_caller]
+    pub fn matmul<A, B, X0, X1, X2>(&mut self, a: View<A, (X0, X1)>, b: View<B, (X1, X2)>) -> Tensor<NodeId, (X0, X2)>
+    where
+        A: DerefSlice<Item = NodeId>,
+        B: DerefSlice<Item = NodeId>,
+        X0: Index,
+        X1: Index,
+        X2: Index,
+    {
+        let ((x0, x1), (b_x1, x2)) = (a.shape(), b.shape());
+        assert!(x1 == b_x1, "inner dimensions of a matrix product differ");
+        Tensor::from_fn((x0, x2), |(i0, i2)| {
+            let row = x1.indices().map(|i1| a[(i0, i1)]);
+            let column = x1.indices().map(|i1| b[(i1, i2)]);
+            self.dot(row, column)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        engine::{Parameter, Values},
+        impl_index,
+    };
+
+    impl_index!(Sample);
+    impl_index!(Input);
+    impl_index!(Output);
+
+    #[test]
+    fn constructors_and_reductions() {
+        let t = Tensor::from_fn((2, 3), |(i, j)| (i * 3 + j) as f64);
+        assert_eq!(t[(1, 2)], 5.0);
+        assert_eq!(t.row(1).copied().collect::<Vec<_>>(), [3.0, 4.0, 5.0]);
+        let enumerated: Vec<_> = t.iter_enumerate().map(|(index, &x)| (index, x)).collect();
+        assert_eq!(enumerated[4], ((1, 1), 4.0));
+        assert_eq!(t.sum(), 15.0);
+        assert_eq!(t.mean(), 2.5);
+        assert_eq!(t.max(), 5.0);
+        assert_eq!(t.map(|&x| x + 1.0).product(), 720.0);
+        assert_eq!(Tensor::<f64, _>::zeros((2,)).as_slice(), &[0.0, 0.0]);
+        assert_eq!(Tensor::<f64, _>::ones((1, 2)).sum(), 2.0);
+        assert_eq!(t.clone().reshape((3, 2)).as_slice(), t.as_slice());
+
+        let transposed = t.transpose();
+        assert_eq!(transposed.shape(), (3, 2));
+        assert_eq!(transposed[(2, 1)], t[(1, 2)]);
+    }
+
+    #[test]
+    fn arithmetic_and_matmul() {
+        let matrix = |rows: [[f64; 2]; 2]| Tensor::from_fn((2, 2), |(i, j)| rows[i][j]);
+        let a = matrix([[1.0, 2.0], [3.0, 4.0]]);
+        let b = matrix([[0.5, -1.0], [2.0, 0.0]]);
+        assert_eq!(&a + &b, matrix([[1.5, 1.0], [5.0, 4.0]]));
+        assert_eq!(&a * &b, matrix([[0.5, -2.0], [6.0, 0.0]]));
+        assert_eq!(a.clone() - b.clone(), matrix([[0.5, 3.0], [1.0, 4.0]]));
+        assert_eq!(a.matmul(&b), matrix([[4.5, -1.0], [9.5, -3.0]]));
+    }
+
+    #[test]
+    #[should_panic(expected = "different shapes")]
+    fn shape_mismatch() {
+        let _ = Tensor::<f64, _>::zeros((2, 3)) + Tensor::<f64, _>::zeros((3, 2));
+    }
+
+    /// A linear layer `relu(x w^T + b)` built from nodes gives the same result
+    /// as computing it on the data directly.
+    #[test]
+    fn same_result_for_data_and_nodes() {
+        let x = Tensor::from_fn((Sample(2), Input(3)), |(s, i)| (s.0 as f64 - i.0 as f64) * 0.5);
+        let w = Tensor::from_fn((Output(2), Input(3)), |(o, i)| o.0 as f64 + 0.25 * i.0 as f64 - 0.5);
+        let b = Tensor::full((Sample(2), Output(2)), -0.75);
+        let expected = (&x.matmul(&w.transpose()) + &b).map(|&y| y.max(0.0));
+
+        let mut ops = Operations::default();
+        let x_nodes = Tensor::from_fn(x.shape(), |_| ops.insert(crate::engine::Input));
+        let w_nodes = Tensor::from_fn(w.shape(), |_| ops.insert(Parameter));
+        let bias = ops.constant(-0.75);
+        let b_nodes = Tensor::full(b.shape(), bias);
+        let product = ops.matmul(x_nodes.view(), w_nodes.transpose().view());
+        let sum = ops.insert(&product + &b_nodes);
+        let y = ops.insert(sum.view().relu());
+
+        let mut values = Values::new(ops.len());
+        let data = x.iter().chain(&w);
+        for (&value, &node) in data.zip(x_nodes.iter().chain(&w_nodes)) {
+            values[node] = value;
+        }
+        ops.forward(&mut values);
+        assert_eq!(Tensor::from(y).map(|&node| values[node]), expected);
+    }
+}
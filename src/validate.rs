@@ -0,0 +1,242 @@
+use std::{fmt, ops::Range};
+
+use crate::{
+    engine::{NodeId, Nullary, Op, Operations},
+    metadata::{DisplayWithNames, NodeDisplay, WithNames},
+};
+
+/// A structural problem found by `Operations::validate`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Problem {
+    /// An operand refers to the node itself or to a later node, so `forward`
+    /// would read its value before it is computed. This can only happen by
+    /// modifying nodes through `IndexMut`.
+    ForwardReference { node: NodeId, operand: NodeId },
+    /// An output passed to `validate_outputs` is not a node in the graph.
+    OutputOutOfRange { output: NodeId },
+    /// A node that is not an output and whose value is never read.
+    UnusedNode { node: NodeId },
+    /// A parameter that none of the outputs depend on, so its gradient is
+    /// always zero.
+    UnreachableParameter { parameter: NodeId },
+}
+
+impl Problem {
+    /// Errors make the graph unusable, the other problems are warnings about
+    /// wasted work.
+    #[inline]
+    pub fn is_error(&self) -> bool {
+        matches!(
+            self,
+            Problem::ForwardReference { .. } | Problem::OutputOutOfRange { .. }
+        )
+    }
+}
+
+impl Problem {
+    /// Displays the problem with the nodes of `ops` shown by name, see
+    /// `Operations::display_node`.
+    #[inline]
+    pub fn display<'a>(&'a self, ops: &'a Operations) -> impl fmt::Display + 'a {
+        WithNames {
+            value: self,
+            ops: Some(ops),
+        }
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.fmt_with_names(f, None)
+    }
+}
+
+impl DisplayWithNames for Problem {
+    fn fmt_with_names(&self, f: &mut fmt::Formatter<'_>, ops: Option<&Operations>) -> fmt::Result {
+        let display = |node| NodeDisplay { ops, node };
+        match *self {
+            Problem::ForwardReference { node, operand } => write!(
+                f,
+                "node {} reads node {} which is not computed before it",
+                display(node),
+                display(operand)
+            ),
+            Problem::OutputOutOfRange { output } => {
+                write!(f, "output {} is not a node in the graph", display(output))
+            }
+            Problem::UnusedNode { node } => write!(f, "node {} is never used", display(node)),
+            Problem::UnreachableParameter { parameter } => {
+                write!(f, "parameter {} does not contribute to any output", display(parameter))
+            }
+        }
+    }
+}
+
+impl Operations {
+    /// Checks the structure of the graph, treating every operation that is not
+    /// read by another node as an output. The problems are sorted by node.
+    ///
+    /// With these outputs, the unused nodes are leaves that are never read and
+    /// the unreachable parameters are parameters that are never read.
+    pub fn validate(&self) -> Vec<Problem> {
+        self.validate_impl(None)
+    }
+
+    /// Checks the structure of the graph for computing `outputs`, which
+    /// additionally reports operations that are not needed for them.
+    pub fn validate_outputs(&self, outputs: &[NodeId]) -> Vec<Problem> {
+        self.validate_impl(Some(outputs))
+    }
+
+    fn validate_impl(&self, outputs: Option<&[NodeId]>) -> Vec<Problem> {
+        let len = self.len();
+        let mut problems = Vec::new();
+
+        let mut is_used = vec![false; len];
+        for node in self.nodes() {
+            for operand in self[node].operands() {
+                if usize::from(operand) < usize::from(node) {
+                    is_used[usize::from(operand)] = true;
+                }
+            }
+        }
+
+        let mut is_output = vec![false; len];
+        match outputs {
+            Some(outputs) => {
+                for &output in outputs {
+                    match is_output.get_mut(usize::from(output)) {
+                        Some(is_output) => *is_output = true,
+                        None => problems.push(Problem::OutputOutOfRange { output }),
+                    }
+                }
+            }
+            None => {
+                for node in self.nodes() {
+                    is_output[usize::from(node)] = !is_used[usize::from(node)] && !matches!(self[node], Op::Nullary(_));
+                }
+            }
+        }
+
+        let mut is_needed = is_output.clone();
+        for node in self.nodes().rev() {
+            if is_needed[usize::from(node)] {
+                for operand in self[node].operands() {
+                    if usize::from(operand) < usize::from(node) {
+                        is_needed[usize::from(operand)] = true;
+                    }
+                }
+            }
+        }
+
+        for node in self.nodes() {
+            let index = usize::from(node);
+            problems.extend(self.forward_references(index..index + 1));
+            if matches!(self[node], Op::Nullary(Nullary::Parameter)) {
+                if !is_needed[index] {
+                    problems.push(Problem::UnreachableParameter { parameter: node });
+                }
+            } else if !is_used[index] && !is_output[index] {
+                problems.push(Problem::UnusedNode { node });
+            }
+        }
+
+        problems
+    }
+
+    fn forward_references(&self, range: Range<usize>) -> impl Iterator<Item = Problem> + '_ {
+        range.map(NodeId::from).flat_map(move |node| {
+            self[node]
+                .operands()
+                .filter(move |&operand| usize::from(operand) >= usize::from(node))
+                .map(move |operand| Problem::ForwardReference { node, operand })
+        })
+    }
+
+    /// Panics in debug builds if a node from `start` onwards reads a node that
+    /// is not computed before it. Called after passes that create or rewrite
+    /// nodes.
+    #[inline]
+    #[track_caller]
+    pub(crate) fn debug_assert_valid_from(&self, start: usize) {
+        if cfg!(debug_assertions)
+            && let Some(problem) = self.forward_references(start..self.len()).next()
+        {
+            panic!("invalid graph: {problem}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{Input, Parameter, Unary};
+
+    #[test]
+    fn valid_graph() {
+        let mut ops = Operations::default();
+        let (x, w) = ops.insert((Input, Parameter));
+        let y = ops.insert((w * x).tanh());
+        assert_eq!(ops.validate(), []);
+        assert_eq!(ops.validate_outputs(&[y]), []);
+    }
+
+    #[test]
+    fn problems() {
+        let mut ops = Operations::default();
+        let (x, unused_input) = ops.insert((Input, Input));
+        let [w, unused_parameter, dead_parameter] = ops.insert([Parameter; 3]);
+        let y = ops.insert(w * x);
+        let dead = ops.insert(dead_parameter.exp());
+
+        assert_eq!(
+            ops.validate(),
+            [
+                Problem::UnusedNode { node: unused_input },
+                Problem::UnreachableParameter {
+                    parameter: unused_parameter
+                },
+            ]
+        );
+        assert_eq!(
+            ops.validate_outputs(&[y, NodeId::from(100)]),
+            [
+                Problem::OutputOutOfRange {
+                    output: NodeId::from(100)
+                },
+                Problem::UnusedNode { node: unused_input },
+                Problem::UnreachableParameter {
+                    parameter: unused_parameter
+                },
+                Problem::UnreachableParameter {
+                    parameter: dead_parameter
+                },
+                Problem::UnusedNode { node: dead },
+            ]
+        );
+    }
+
+    #[test]
+    fn forward_reference() {
+        let mut ops = Operations::default();
+        let x = ops.var();
+        let y = ops.insert(x.exp());
+        let z = ops.insert(y.ln());
+        ops[y] = Op::Unary(Unary::Exp, z);
+
+        let problems = ops.validate();
+        assert_eq!(
+            problems,
+            [
+                Problem::UnusedNode { node: x },
+                Problem::ForwardReference { node: y, operand: z }
+            ]
+        );
+        assert!(!problems[0].is_error());
+        assert!(problems[1].is_error());
+        assert_eq!(
+            problems[1].to_string(),
+            "node %1 reads node %2 which is not computed before it"
+        );
+    }
+}
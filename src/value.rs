@@ -0,0 +1,393 @@
+//! A dynamic API in the style of the original micrograd, where every value
+//! knows its graph and operations are recorded as they happen:
+//!
+//! ```
+//! use micrograd_rs::value::Tape;
+//!
+//! let tape = Tape::new();
+//! let a = tape.value(2.0);
+//! let b = tape.value(-3.0);
+//! let c = &a * &b + 1.0;
+//! c.backward();
+//! assert_eq!(c.data(), -5.0);
+//! assert_eq!(a.grad(), -3.0);
+//! ```
+use std::{
+    cell::{Ref, RefCell},
+    fmt,
+    ops::{Add, Div, Mul, Neg, Sub},
+    rc::Rc,
+};
+
+use crate::{
+    engine::{Binary, Constant, Gradients, NodeId, Nullary, Op, Operations, Unary, Values},
+    syntax::{call_with_binary_variants, call_with_unary_variants},
+};
+
+#[derive(Debug, Default)]
+struct Recording {
+    ops: Operations,
+    values: Values,
+    gradients: Gradients,
+}
+
+impl Recording {
+    /// Inserts `op` and computes its value right away.
+    #[track_caller]
+    fn push(&mut self, op: Op) -> NodeId {
+        let node = self.ops.insert(op);
+        let value = match op {
+            Op::Nullary(Nullary::Var | Nullary::Input | Nullary::Parameter) => f64::NAN,
+            Op::Nullary(Nullary::Constant(constant)) => constant.value(),
+            Op::Unary(unary, a) => unary.forward(self.values[a]),
+            Op::Binary(binary, (a, b)) => binary.forward(self.values[a], self.values[b]),
+        };
+        self.values.resize(self.ops.len(), value);
+        self.gradients.resize(self.ops.len(), 0.0);
+        node
+    }
+}
+
+/// The graph recorded by `Value`s along with their data and gradients. Cloning
+/// a tape gives another handle to the same graph.
+#[derive(Debug, Default, Clone)]
+pub struct Tape(Rc<RefCell<Recording>>);
+
+impl Tape {
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records a new variable holding `data`.
+    #[track_caller]
+    pub fn value(&self, data: f64) -> Value {
+        let node = self.0.borrow_mut().push(Op::Nullary(Nullary::Var));
+        self.0.borrow_mut().values[node] = data;
+        self.value_of(node)
+    }
+
+    /// Records a new constant, which does not get a gradient.
+    #[track_caller]
+    pub fn constant(&self, data: f64) -> Value {
+        let node = self
+            .0
+            .borrow_mut()
+            .push(Op::Nullary(Nullary::Constant(Constant::new(data))));
+        self.value_of(node)
+    }
+
+    #[inline]
+    fn value_of(&self, node: NodeId) -> Value {
+        Value {
+            tape: self.clone(),
+            node,
+        }
+    }
+
+    /// The recorded graph, for example to export it with graphviz.
+    #[inline]
+    pub fn ops(&self) -> Ref<'_, Operations> {
+        Ref::map(self.0.borrow(), |recording| &recording.ops)
+    }
+
+    /// Recomputes every value after the data of a variable was changed with
+    /// `Value::set_data`.
+    pub fn forward(&self) {
+        let recording = &mut *self.0.borrow_mut();
+        recording.ops.forward(&mut recording.values);
+    }
+}
+
+/// A handle to a node in a `Tape`. Operations on values record a new node and
+/// compute its data right away with the same rules as `Operations::forward`.
+///
+/// Values from different tapes cannot be combined.
+#[derive(Clone)]
+pub struct Value {
+    tape: Tape,
+    node: NodeId,
+}
+
+macro_rules! unary_method {
+    (Neg, neg) => {};
+    ($V:ident, $v:ident) => {
+        #[inline]
+        #[track_caller]
+        pub fn $v(&self) -> Value {
+            self.unary(Unary::$V)
+        }
+    };
+}
+
+macro_rules! binary_method {
+    (Add, add) => {};
+    (Sub, sub) => {};
+    (Mul, mul) => {};
+    (Div, div) => {};
+    ($V:ident, $v:ident) => {
+        #[inline]
+        #[track_caller]
+        pub fn $v(&self, rhs: &Value) -> Value {
+            self.binary(Binary::$V, rhs)
+        }
+    };
+}
+
+impl Value {
+    #[inline]
+    pub fn tape(&self) -> &Tape {
+        &self.tape
+    }
+
+    #[inline]
+    pub fn node(&self) -> NodeId {
+        self.node
+    }
+
+    #[inline]
+    #[track_caller]
+    pub fn data(&self) -> f64 {
+        self.tape.0.borrow().values[self.node]
+    }
+
+    /// Changes the data of a variable, like a weight during gradient descent.
+    /// Values computed from it are only updated by `Tape::forward`.
+    ///
+    /// Panics if this value is not a variable.
+    #[track_caller]
+    pub fn set_data(&self, data: f64) {
+        let recording = &mut *self.tape.0.borrow_mut();
+        assert!(
+            matches!(recording.ops[self.node], Op::Nullary(Nullary::Var)),
+            "only the data of variables can be set"
+        );
+        recording.values[self.node] = data;
+    }
+
+    /// The gradient computed by the last call to `backward` on any value of the
+    /// tape, or zero if there was none.
+    #[inline]
+    #[track_caller]
+    pub fn grad(&self) -> f64 {
+        self.tape.0.borrow().gradients[self.node]
+    }
+
+    /// Computes the gradient of this value with respect to every value it was
+    /// computed from, replacing the gradients of a previous call.
+    #[track_caller]
+    pub fn backward(&self) {
+        let recording = &mut *self.tape.0.borrow_mut();
+        recording
+            .ops
+            .backward(&recording.values, &mut recording.gradients, self.node, 1.0);
+    }
+
+    #[track_caller]
+    pub fn unary(&self, op: Unary) -> Value {
+        let node = self.tape.0.borrow_mut().push(Op::Unary(op, self.node));
+        self.tape.value_of(node)
+    }
+
+    #[track_caller]
+    pub fn binary(&self, op: Binary, rhs: &Value) -> Value {
+        assert!(
+            Rc::ptr_eq(&self.tape.0, &rhs.tape.0),
+            "operands of an operation belong to different tapes"
+        );
+        let node = self.tape.0.borrow_mut().push(Op::Binary(op, (self.node, rhs.node)));
+        self.tape.value_of(node)
+    }
+
+    /// Raises this value to a constant power.
+    #[track_caller]
+    pub fn powf(&self, exponent: f64) -> Value {
+        self.pow(&self.tape.constant(exponent))
+    }
+
+    call_with_unary_variants!(unary_method);
+    call_with_binary_variants!(binary_method);
+}
+
+impl fmt::Debug for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let recording = self.tape.0.borrow();
+        f.debug_struct("Value")
+            .field("data", &recording.values[self.node])
+            .field("grad", &recording.gradients[self.node])
+            .finish()
+    }
+}
+
+impl Neg for &Value {
+    type Output = Value;
+
+    #[inline]
+    #[track_caller]
+    fn neg(self) -> Self::Output {
+        self.unary(Unary::Neg)
+    }
+}
+
+impl Neg for Value {
+    type Output = Value;
+
+    #[inline]
+    #[track_caller]
+    fn neg(self) -> Self::Output {
+        -&self
+    }
+}
+
+macro_rules! impl_value_op {
+    ($V:ident, $v:ident) => {
+        impl $V<&Value> for &Value {
+            type Output = Value;
+
+            #[inline]
+            #[track_caller]
+            fn $v(self, rhs: &Value) -> Self::Output {
+                self.binary(Binary::$V, rhs)
+            }
+        }
+
+        impl $V<Value> for &Value {
+            type Output = Value;
+
+            #[inline]
+            #[track_caller]
+            fn $v(self, rhs: Value) -> Self::Output {
+                self.binary(Binary::$V, &rhs)
+            }
+        }
+
+        impl $V<&Value> for Value {
+            type Output = Value;
+
+            #[inline]
+            #[track_caller]
+            fn $v(self, rhs: &Value) -> Self::Output {
+                self.binary(Binary::$V, rhs)
+            }
+        }
+
+        impl $V<Value> for Value {
+            type Output = Value;
+
+            #[inline]
+            #[track_caller]
+            fn $v(self, rhs: Value) -> Self::Output {
+                self.binary(Binary::$V, &rhs)
+            }
+        }
+
+        impl $V<f64> for &Value {
+            type Output = Value;
+
+            #[inline]
+            #[track_caller]
+            fn $v(self, rhs: f64) -> Self::Output {
+                self.binary(Binary::$V, &self.tape.constant(rhs))
+            }
+        }
+
+        impl $V<f64> for Value {
+            type Output = Value;
+
+            #[inline]
+            #[track_caller]
+            fn $v(self, rhs: f64) -> Self::Output {
+                (&self).$v(rhs)
+            }
+        }
+
+        impl $V<&Value> for f64 {
+            type Output = Value;
+
+            #[inline]
+            #[track_caller]
+            fn $v(self, rhs: &Value) -> Self::Output {
+                rhs.tape.constant(self).binary(Binary::$V, rhs)
+            }
+        }
+
+        impl $V<Value> for f64 {
+            type Output = Value;
+
+            #[inline]
+            #[track_caller]
+            fn $v(self, rhs: Value) -> Self::Output {
+                self.$v(&rhs)
+            }
+        }
+    };
+}
+impl_value_op!(Add, add);
+impl_value_op!(Sub, sub);
+impl_value_op!(Mul, mul);
+impl_value_op!(Div, div);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() < 1e-4, "{actual} != {expected}");
+    }
+
+    /// The example from the readme of the original micrograd.
+    #[test]
+    fn micrograd_example() {
+        let tape = Tape::new();
+        let a = tape.value(-4.0);
+        let b = tape.value(2.0);
+        let c = &a + &b;
+        let d = &a * &b + b.powf(3.0);
+        let c = &c + (&c + 1.0);
+        let c = &c + (1.0 + &c + -&a);
+        let d = &d + (&d * 2.0 + (&b + &a).relu());
+        let d = &d + (3.0 * &d + (&b - &a).relu());
+        let e = &c - &d;
+        let f = e.pow_2();
+        let g = &f / 2.0;
+        let g = &g + 10.0 / &f;
+
+        assert_close(g.data(), 24.7041);
+        g.backward();
+        assert_close(a.grad(), 138.8338);
+        assert_close(b.grad(), 645.5773);
+    }
+
+    #[test]
+    fn gradient_descent() {
+        let tape = Tape::new();
+        let w = tape.value(0.0);
+        let loss = (&w - 3.0).pow_2();
+        for _ in 0..100 {
+            tape.forward();
+            loss.backward();
+            w.set_data(w.data() - 0.1 * w.grad());
+        }
+        assert_close(w.data(), 3.0);
+        assert_eq!(tape.ops().len(), 4);
+    }
+
+    #[test]
+    fn every_operation() {
+        let tape = Tape::new();
+        let (a, b) = (tape.value(0.75), tape.value(1.25));
+        for op in Unary::ALL {
+            assert_eq!(a.unary(op).data(), op.forward(0.75), "{}", op.name());
+        }
+        for op in Binary::ALL {
+            assert_eq!(a.binary(op, &b).data(), op.forward(0.75, 1.25), "{}", op.name());
+        }
+        assert_eq!(a.scale_grad(&b).data(), 0.75);
+    }
+
+    #[test]
+    #[should_panic(expected = "different tapes")]
+    fn different_tapes() {
+        let _ = Tape::new().value(1.0) + Tape::new().value(2.0);
+    }
+}
@@ -97,6 +97,16 @@ where
 impl<X> std::iter::FusedIterator for IndexTupleIter<X> where X: IndexTuple {}
 impl<X> ExactSizeIterator for IndexTupleIter<X> where X: IndexTuple {}
 
+/// The shape of a multi-dimensional array, also used as an index into it.
+///
+/// `flatten` stores elements in column-major order: in a shape `(X0, X1)` the
+/// element `(i0, i1)` is stored at `i1 * X0 + i0`. The parameters of a
+/// `FullyConnectedLayer`, and with them the files written by
+/// `nn::serialization`, depend on this layout.
+///
+/// `indices` and `unflatten` go through the indices in row-major order, so
+/// they do not follow the storage order of `flatten`. Index into the array to
+/// pair an index with its element.
 pub trait IndexTuple: Copy {
     fn flatten(&self, index: Self) -> usize;
     fn unflatten(&self, index: usize) -> Self;
@@ -141,7 +151,7 @@ where
     }
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub struct View<A, X> {
     data: A,
     shape: X,
@@ -168,6 +178,10 @@ impl<A, X> View<A, X> {
         &self.data
     }
 
+    pub fn into_data(self) -> A {
+        self.data
+    }
+
     pub fn reindex<Y, F>(self, f: F) -> View<A, Y>
     where
         A: DerefSlice,
@@ -225,6 +239,15 @@ where
         let shape = self.shape;
         self.data.iter().enumerate().map_t0(move |index| shape.unflatten(index))
     }
+
+    /// Applies `f` to every element and returns an owned view of the results
+    /// with the same shape.
+    pub fn map<U, F>(&self, f: F) -> View<Vec<U>, X>
+    where
+        F: FnMut(&<A as DerefSlice>::Item) -> U,
+    {
+        View::new(self.data.iter().map(f).collect(), self.shape)
+    }
 }
 
 impl<A, X> View<A, X>
@@ -336,4 +359,16 @@ mod tests {
         assert_eq!(shape.indices().last(), Some((A(1), B(2))));
         assert_eq!(shape.indices().nth(3), Some((A(1), B(0))));
     }
+
+    #[test]
+    fn index_tuple_layout_is_column_major() {
+        let shape = (A(2), B(3));
+        assert_eq!(shape.flatten((A(1), B(0))), 1);
+        assert_eq!(shape.flatten((A(0), B(1))), 2);
+        assert_eq!(shape.flatten((A(1), B(2))), 5);
+
+        let view = View::new(vec![0, 1, 2, 3, 4, 5], shape);
+        assert_eq!(view[(A(1), B(0))], 1);
+        assert_eq!(view[(A(0), B(2))], 4);
+    }
 }